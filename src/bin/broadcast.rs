@@ -0,0 +1,752 @@
+use maelstrom_challenge::{contexts, errors, nodes};
+
+/// How broadcast messages are gossiped to neighbours.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BroadcastMode {
+    /// One message per request, retried with backoff until acknowledged.
+    Eager,
+    /// Messages are batched per neighbour and flushed on an interval.
+    Batched,
+}
+
+/// How broadcast gossips, see [`BroadcastConfig::from_env`] for the variables.
+#[derive(Debug, Clone, Copy)]
+struct BroadcastConfig {
+    /// Eager or batched gossip.
+    mode: BroadcastMode,
+    /// The longest a batch waits before it is flushed.
+    batch_interval: std::time::Duration,
+    /// A batch is flushed as soon as it holds this many messages.
+    max_batch_size: usize,
+    /// The median latency batching aims for, it shortens the flush interval.
+    target_median_latency: std::time::Duration,
+    /// The maximum latency batching aims for, it shortens the flush interval.
+    target_max_latency: std::time::Duration,
+    /// How often each neighbour is sent every message, if at all.
+    anti_entropy_interval: Option<std::time::Duration>,
+}
+
+impl Default for BroadcastConfig {
+    fn default() -> Self {
+        Self {
+            mode: BroadcastMode::Eager,
+            batch_interval: std::time::Duration::from_millis(200),
+            max_batch_size: 64,
+            target_median_latency: std::time::Duration::from_millis(400),
+            target_max_latency: std::time::Duration::from_millis(600),
+            anti_entropy_interval: Some(ANTI_ENTROPY_INTERVAL),
+        }
+    }
+}
+
+impl BroadcastConfig {
+    fn millis_from_env(name: &str, default: std::time::Duration) -> std::time::Duration {
+        std::env::var(name)
+            .map(|value| {
+                std::time::Duration::from_millis(
+                    value
+                        .parse()
+                        .unwrap_or_else(|_| panic!("{name} should be a number of milliseconds")),
+                )
+            })
+            .unwrap_or(default)
+    }
+
+    /// Reads `BROADCAST_MODE`, `BROADCAST_BATCH_INTERVAL_MS`,
+    /// `BROADCAST_MAX_BATCH_SIZE`, `BROADCAST_TARGET_MEDIAN_LATENCY_MS`,
+    /// `BROADCAST_TARGET_MAX_LATENCY_MS` and `BROADCAST_ANTI_ENTROPY_INTERVAL_MS`,
+    /// with defaults for those that are unset. An anti-entropy interval of 0
+    /// turns anti-entropy off.
+    fn from_env() -> Self {
+        let default = Self::default();
+        let mode = match std::env::var("BROADCAST_MODE").as_deref() {
+            Ok("batched") => BroadcastMode::Batched,
+            Ok("eager") | Err(_) => BroadcastMode::Eager,
+            Ok(mode) => panic!("unknown BROADCAST_MODE {mode}, expect eager or batched"),
+        };
+        // batches are re-queued until they are acknowledged, so batched mode
+        // needs no anti-entropy unless it is asked for
+        let anti_entropy_interval = match mode {
+            BroadcastMode::Eager => ANTI_ENTROPY_INTERVAL,
+            BroadcastMode::Batched => std::time::Duration::ZERO,
+        };
+        Self {
+            mode,
+            batch_interval: Self::millis_from_env(
+                "BROADCAST_BATCH_INTERVAL_MS",
+                default.batch_interval,
+            ),
+            max_batch_size: std::env::var("BROADCAST_MAX_BATCH_SIZE")
+                .map(|value| {
+                    value
+                        .parse()
+                        .expect("BROADCAST_MAX_BATCH_SIZE should be a number")
+                })
+                .unwrap_or(default.max_batch_size),
+            target_median_latency: Self::millis_from_env(
+                "BROADCAST_TARGET_MEDIAN_LATENCY_MS",
+                default.target_median_latency,
+            ),
+            target_max_latency: Self::millis_from_env(
+                "BROADCAST_TARGET_MAX_LATENCY_MS",
+                default.target_max_latency,
+            ),
+            anti_entropy_interval: Some(Self::millis_from_env(
+                "BROADCAST_ANTI_ENTROPY_INTERVAL_MS",
+                anti_entropy_interval,
+            ))
+            .filter(|interval| !interval.is_zero()),
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct BroadcastRequest {
+    message: usize,
+}
+
+#[derive(serde::Serialize)]
+struct BroadcastResponse {}
+
+#[derive(serde::Deserialize)]
+struct ReadRequest {}
+
+#[derive(serde::Serialize)]
+struct ReadResponse {
+    messages: Vec<usize>,
+}
+
+#[derive(serde::Deserialize)]
+struct TopologyRequest {
+    topology: std::collections::BTreeMap<String, Vec<String>>,
+}
+
+#[derive(serde::Serialize)]
+struct TopologyResponse {}
+
+#[derive(serde::Deserialize)]
+struct AntiEntropyRequest {
+    messages: Vec<usize>,
+}
+
+#[derive(serde::Serialize)]
+struct AntiEntropyResponse {
+    messages: Vec<usize>,
+}
+
+#[derive(serde::Deserialize)]
+struct GossipBatchRequest {
+    messages: Vec<usize>,
+}
+
+#[derive(serde::Serialize)]
+struct GossipBatchResponse {}
+
+/// Requests a node sends its neighbours.
+#[derive(serde::Serialize)]
+#[serde(tag = "type")]
+enum Gossip {
+    #[serde(rename = "broadcast")]
+    Message { message: usize },
+    #[serde(rename = "gossip_batch")]
+    Batch { messages: Vec<usize> },
+    #[serde(rename = "anti_entropy")]
+    AntiEntropy { messages: Vec<usize> },
+}
+
+/// Replies of the neighbours, unknown fields are ignored.
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "type")]
+enum GossipReply {
+    #[serde(rename = "broadcast_ok")]
+    Message,
+    #[serde(rename = "gossip_batch_ok")]
+    Batch,
+    #[serde(rename = "anti_entropy_ok")]
+    AntiEntropy { messages: Vec<usize> },
+    #[serde(rename = "error")]
+    Error {
+        code: u64,
+        #[serde(default)]
+        text: String,
+    },
+}
+
+const GOSSIP_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+const GOSSIP_INITIAL_BACKOFF: std::time::Duration = std::time::Duration::from_millis(100);
+const GOSSIP_MAX_BACKOFF: std::time::Duration = std::time::Duration::from_secs(5);
+const ANTI_ENTROPY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// The messages of a node and how it passes them on.
+#[derive(Debug)]
+struct Broadcast {
+    config: BroadcastConfig,
+    topology: std::collections::BTreeMap<String, Vec<String>>,
+    messages: std::collections::BTreeSet<usize>,
+    batches: std::collections::BTreeMap<String, std::collections::BTreeSet<usize>>,
+}
+
+type Context = contexts::Context<Broadcast>;
+
+impl Broadcast {
+    fn new(config: BroadcastConfig) -> Self {
+        Self {
+            config,
+            topology: std::collections::BTreeMap::new(),
+            messages: std::collections::BTreeSet::new(),
+            batches: std::collections::BTreeMap::new(),
+        }
+    }
+
+    fn hop_distances(&self) -> Vec<usize> {
+        let mut distances = Vec::new();
+        for source in self.topology.keys() {
+            let mut seen = std::collections::BTreeMap::from([(source, 0)]);
+            let mut queue = std::collections::VecDeque::from([source]);
+            while let Some(node) = queue.pop_front() {
+                let distance = seen[node];
+                for neighbour in self.topology.get(node).into_iter().flatten() {
+                    if !seen.contains_key(neighbour) {
+                        seen.insert(neighbour, distance + 1);
+                        queue.push_back(neighbour);
+                    }
+                }
+            }
+            distances.extend(seen.into_values().filter(|distance| *distance > 0));
+        }
+        distances
+    }
+
+    // a value waits on average half an interval and at most a full interval
+    // at every hop, so the interval is capped by the latency budget per hop
+    fn flush_interval(&self) -> std::time::Duration {
+        let distances = self.hop_distances();
+        let Some(diameter) = distances.iter().max().copied() else {
+            return self.config.batch_interval;
+        };
+        let mean = distances.iter().sum::<usize>() as f64 / distances.len() as f64;
+        let for_max = self.config.target_max_latency / diameter as u32;
+        let for_median = self
+            .config
+            .target_median_latency
+            .mul_f64(2.0 / mean.max(1.0));
+        self.config.batch_interval.min(for_max).min(for_median)
+    }
+
+    fn has_topology(&self) -> bool {
+        !self.topology.is_empty()
+    }
+
+    /// Records a broadcast message, returns whether it is new.
+    fn push_message(&mut self, message: usize) -> bool {
+        self.messages.insert(message)
+    }
+
+    /// Every broadcast message this node has seen, in order.
+    fn messages(&self) -> Vec<usize> {
+        self.messages.iter().copied().collect()
+    }
+
+    /// Merges the messages a neighbour has seen and returns those it is missing.
+    fn reconcile(&mut self, messages: Vec<usize>) -> Vec<usize> {
+        let theirs: std::collections::BTreeSet<usize> = messages.into_iter().collect();
+        let missing = self.messages.difference(&theirs).copied().collect();
+        self.messages.extend(theirs);
+        missing
+    }
+}
+
+fn broadcast(
+    context: &mut Context,
+    request: nodes::Request<BroadcastRequest>,
+) -> Result<BroadcastResponse, errors::Error> {
+    let message = request.body.message;
+    if context.state.push_message(message) {
+        gossip(context, message, request.src.as_str())?;
+    }
+    Ok(BroadcastResponse {})
+}
+
+fn read(
+    context: &mut Context,
+    _: nodes::Request<ReadRequest>,
+) -> Result<ReadResponse, errors::Error> {
+    Ok(ReadResponse {
+        messages: context.state.messages(),
+    })
+}
+
+fn topology(
+    context: &mut Context,
+    request: nodes::Request<TopologyRequest>,
+) -> Result<TopologyResponse, errors::Error> {
+    let graph = request.body.topology;
+    for node in graph
+        .iter()
+        .flat_map(|(node, neighbours)| std::iter::once(node).chain(neighbours))
+    {
+        if !context.is_member(node)? {
+            return Err(errors::Error::malformed_request(format!(
+                "topology references unknown node {node}"
+            )));
+        }
+    }
+    set_topology(context, graph);
+    Ok(TopologyResponse {})
+}
+
+fn anti_entropy(
+    context: &mut Context,
+    request: nodes::Request<AntiEntropyRequest>,
+) -> Result<AntiEntropyResponse, errors::Error> {
+    Ok(AntiEntropyResponse {
+        messages: context.state.reconcile(request.body.messages),
+    })
+}
+
+fn gossip_batch(
+    context: &mut Context,
+    request: nodes::Request<GossipBatchRequest>,
+) -> Result<GossipBatchResponse, errors::Error> {
+    for message in request.body.messages {
+        if context.state.push_message(message) {
+            gossip(context, message, request.src.as_str())?;
+        }
+    }
+    Ok(GossipBatchResponse {})
+}
+
+/// Replaces the topology of the cluster. The first topology starts
+/// anti-entropy and, in batched mode, the flushing of batches.
+fn set_topology(context: &mut Context, graph: std::collections::BTreeMap<String, Vec<String>>) {
+    if !context.state.has_topology() {
+        if let Some(interval) = context.state.config.anti_entropy_interval {
+            context.schedule_periodic(interval, move |context| {
+                anti_entropy_round(context, interval)
+            });
+        }
+        if context.state.config.mode == BroadcastMode::Batched {
+            context.schedule(context.state.config.batch_interval, flush_batches);
+        }
+    }
+    context.state.topology = graph
+}
+
+/// The neighbours of this node in the topology.
+fn neighbours(context: &Context) -> Result<Vec<String>, contexts::ContextWhoamiError> {
+    Ok(context
+        .state
+        .topology
+        .get(context.whoami()?)
+        .cloned()
+        .unwrap_or_default())
+}
+
+/// Passes `message` on to every neighbour but `origin`.
+fn gossip(
+    context: &mut Context,
+    message: usize,
+    origin: &str,
+) -> Result<(), contexts::ContextWhoamiError> {
+    for neighbour in neighbours(context)? {
+        if neighbour == origin {
+            continue;
+        }
+        match context.state.config.mode {
+            BroadcastMode::Eager => deliver(context, neighbour, message, GOSSIP_INITIAL_BACKOFF)?,
+            BroadcastMode::Batched => enqueue(context, neighbour, std::iter::once(message))?,
+        }
+    }
+    Ok(())
+}
+
+fn enqueue(
+    context: &mut Context,
+    neighbour: String,
+    messages: impl IntoIterator<Item = usize>,
+) -> Result<(), contexts::ContextWhoamiError> {
+    let batch = context.state.batches.entry(neighbour.clone()).or_default();
+    batch.extend(messages);
+    if batch.len() >= context.state.config.max_batch_size {
+        flush_batch(context, neighbour)?;
+    }
+    Ok(())
+}
+
+fn flush_batch(
+    context: &mut Context,
+    neighbour: String,
+) -> Result<(), contexts::ContextWhoamiError> {
+    let Some(batch) = context.state.batches.remove(&neighbour) else {
+        return Ok(());
+    };
+    let peer = neighbour.clone();
+    context.call(
+        neighbour,
+        Gossip::Batch {
+            messages: batch.iter().copied().collect(),
+        },
+        GOSSIP_TIMEOUT,
+        move |context: &mut Context, reply| {
+            if matches!(reply, Ok(GossipReply::Batch)) {
+                return;
+            }
+            context.state.batches.entry(peer).or_default().extend(batch);
+        },
+    )
+}
+
+fn flush_batches(context: &mut Context) {
+    let neighbours: Vec<String> = context.state.batches.keys().cloned().collect();
+    for neighbour in neighbours {
+        flush_batch(context, neighbour).expect("batches are only flushed once initialized");
+    }
+    context.schedule(context.state.flush_interval(), flush_batches);
+}
+
+fn deliver(
+    context: &mut Context,
+    neighbour: String,
+    message: usize,
+    backoff: std::time::Duration,
+) -> Result<(), contexts::ContextWhoamiError> {
+    let peer = neighbour.clone();
+    context.call(
+        neighbour,
+        Gossip::Message { message },
+        GOSSIP_TIMEOUT,
+        move |context: &mut Context, reply| {
+            if matches!(reply, Ok(GossipReply::Message)) {
+                return;
+            }
+            context.schedule(backoff, move |context| {
+                deliver(
+                    context,
+                    peer,
+                    message,
+                    (backoff * 2).min(GOSSIP_MAX_BACKOFF),
+                )
+                .expect("gossip is only sent once initialized")
+            });
+        },
+    )
+}
+
+fn anti_entropy_round(context: &mut Context, interval: std::time::Duration) {
+    let neighbours = neighbours(context).expect("anti-entropy is only scheduled once initialized");
+    for neighbour in neighbours {
+        context
+            .call(
+                neighbour,
+                Gossip::AntiEntropy {
+                    messages: context.state.messages(),
+                },
+                interval,
+                |context: &mut Context, reply| match reply {
+                    Ok(GossipReply::AntiEntropy { messages }) => {
+                        context.state.messages.extend(messages)
+                    }
+                    Ok(GossipReply::Error { code, text }) => {
+                        eprintln!("anti-entropy failed with {code}: {text}")
+                    }
+                    Ok(reply) => eprintln!("ignoring anti-entropy reply: {reply:?}"),
+                    Err(_) => {}
+                },
+            )
+            .expect("anti-entropy is only scheduled once initialized");
+    }
+}
+
+fn main() {
+    let mut node = nodes::Node::new(contexts::Context::new(Broadcast::new(
+        BroadcastConfig::from_env(),
+    )));
+    node.handle("broadcast", broadcast)
+        .handle("read", read)
+        .handle("topology", topology)
+        .handle("anti_entropy", anti_entropy)
+        .handle("gossip_batch", gossip_batch);
+    node.run();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use maelstrom_challenge::rpc;
+
+    fn request<T>(body: T) -> nodes::Request<T> {
+        nodes::Request {
+            src: "c1".to_string(),
+            body,
+        }
+    }
+
+    /// A context for `n1` whose only neighbour is `n2`.
+    fn context(
+        config: BroadcastConfig,
+    ) -> (
+        Context,
+        std::sync::mpsc::Sender<Vec<u8>>,
+        std::sync::mpsc::Receiver<String>,
+    ) {
+        let (rpc, sender, outgoing) = rpc::Rpc::channels();
+        let mut context = contexts::Context::with_rpc(rpc, Broadcast::new(config));
+        context
+            .initialize("n1".to_string(), vec!["n1".to_string(), "n2".to_string()])
+            .unwrap();
+        set_topology(
+            &mut context,
+            [
+                ("n1".to_string(), vec!["n2".to_string()]),
+                ("n2".to_string(), vec!["n1".to_string()]),
+            ]
+            .into(),
+        );
+        (context, sender, outgoing)
+    }
+
+    /// Runs the next callback or timer.
+    fn run_next(context: &mut Context) {
+        match context.next_event() {
+            rpc::Event::Callback(callback, reply) => callback(context, reply),
+            rpc::Event::Timer(timer) => timer(context),
+            _ => panic!("expect a callback or a timer"),
+        }
+    }
+
+    /// The body of the next message the node sent.
+    fn sent(outgoing: &std::sync::mpsc::Receiver<String>) -> serde_json::Value {
+        let output_string = outgoing.try_recv().expect("a message was sent");
+        serde_json::from_str::<serde_json::Value>(&output_string).unwrap()["body"].take()
+    }
+
+    /// Replies to `request` from `n2` with `body`.
+    fn reply(
+        sender: &std::sync::mpsc::Sender<Vec<u8>>,
+        request: &serde_json::Value,
+        mut body: serde_json::Value,
+    ) {
+        body["in_reply_to"] = request["msg_id"].clone();
+        let line = serde_json::json!({"src": "n2", "dest": "n1", "body": body});
+        sender.send(line.to_string().into_bytes()).unwrap();
+    }
+
+    fn eager() -> BroadcastConfig {
+        BroadcastConfig {
+            anti_entropy_interval: None,
+            ..BroadcastConfig::default()
+        }
+    }
+
+    #[test]
+    fn gossips_new_messages_to_neighbours() {
+        let (rpc, _sender, outgoing) = rpc::Rpc::channels();
+        let mut context =
+            contexts::Context::with_rpc(rpc, Broadcast::new(BroadcastConfig::default()));
+        context
+            .initialize("n1".to_string(), vec!["n1".to_string(), "n2".to_string()])
+            .unwrap();
+        let unknown = topology(
+            &mut context,
+            request(TopologyRequest {
+                topology: [("n1".to_string(), vec!["n3".to_string()])].into(),
+            }),
+        );
+        assert!(unknown.is_err());
+        topology(
+            &mut context,
+            request(TopologyRequest {
+                topology: [
+                    ("n1".to_string(), vec!["n2".to_string()]),
+                    ("n2".to_string(), vec!["n1".to_string()]),
+                ]
+                .into(),
+            }),
+        )
+        .unwrap();
+        for _ in 0..2 {
+            broadcast(&mut context, request(BroadcastRequest { message: 5 })).unwrap();
+        }
+        // a message is only gossiped the first time it is seen
+        let gossip = outgoing.try_recv().unwrap();
+        assert!(gossip.contains(r#""dest":"n2""#));
+        assert!(outgoing.try_recv().is_err());
+        let response = read(&mut context, request(ReadRequest {})).unwrap();
+        assert_eq!(response.messages, [5]);
+    }
+
+    #[test]
+    fn retries_gossip_with_a_growing_backoff() {
+        let (mut context, sender, outgoing) = context(eager());
+        broadcast(&mut context, request(BroadcastRequest { message: 5 })).unwrap();
+        let mut backoff = GOSSIP_INITIAL_BACKOFF;
+        for _ in 0..3 {
+            let gossip = sent(&outgoing);
+            reply(
+                &sender,
+                &gossip,
+                serde_json::json!({"type": "error", "code": 11}),
+            );
+            let failed = std::time::Instant::now();
+            run_next(&mut context);
+            run_next(&mut context);
+            assert!(failed.elapsed() >= backoff);
+            backoff *= 2;
+        }
+        let gossip = sent(&outgoing);
+        assert_eq!(gossip["type"], "broadcast");
+        assert_eq!(gossip["message"], 5);
+        reply(
+            &sender,
+            &gossip,
+            serde_json::json!({"type": "broadcast_ok"}),
+        );
+        run_next(&mut context);
+        assert!(outgoing.try_recv().is_err());
+    }
+
+    #[test]
+    fn redelivers_gossip_that_is_not_acknowledged() {
+        let (mut context, _sender, outgoing) = context(eager());
+        let start = std::time::Instant::now();
+        broadcast(&mut context, request(BroadcastRequest { message: 5 })).unwrap();
+        let dropped = sent(&outgoing);
+        // the timeout, then the backoff
+        run_next(&mut context);
+        run_next(&mut context);
+        assert!(start.elapsed() >= GOSSIP_TIMEOUT + GOSSIP_INITIAL_BACKOFF);
+        let retried = sent(&outgoing);
+        assert_eq!(retried["message"], 5);
+        assert_ne!(retried["msg_id"], dropped["msg_id"]);
+    }
+
+    #[test]
+    fn reconciles_with_the_messages_a_neighbour_is_missing() {
+        let mut broadcast = Broadcast::new(BroadcastConfig::default());
+        for message in [1, 2, 3] {
+            broadcast.push_message(message);
+        }
+        assert_eq!(broadcast.reconcile(vec![2, 4]), [1, 3]);
+        assert_eq!(broadcast.messages(), [1, 2, 3, 4]);
+        assert!(broadcast.reconcile(vec![1, 2, 3, 4]).is_empty());
+    }
+
+    #[test]
+    fn merges_the_reply_to_anti_entropy() {
+        let (mut context, sender, outgoing) = context(BroadcastConfig {
+            anti_entropy_interval: Some(std::time::Duration::from_millis(50)),
+            ..BroadcastConfig::default()
+        });
+        context.state.push_message(1);
+        run_next(&mut context);
+        let round = sent(&outgoing);
+        assert_eq!(round["type"], "anti_entropy");
+        assert_eq!(round["messages"], serde_json::json!([1]));
+        reply(
+            &sender,
+            &round,
+            serde_json::json!({"type": "anti_entropy_ok", "messages": [2, 3]}),
+        );
+        run_next(&mut context);
+        assert_eq!(context.state.messages(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn flushes_full_batches_and_requeues_those_that_fail() {
+        let (mut context, sender, outgoing) = context(BroadcastConfig {
+            mode: BroadcastMode::Batched,
+            max_batch_size: 2,
+            anti_entropy_interval: None,
+            ..BroadcastConfig::default()
+        });
+        broadcast(&mut context, request(BroadcastRequest { message: 1 })).unwrap();
+        assert!(outgoing.try_recv().is_err());
+        broadcast(&mut context, request(BroadcastRequest { message: 2 })).unwrap();
+        let batch = sent(&outgoing);
+        assert_eq!(batch["type"], "gossip_batch");
+        assert_eq!(batch["messages"], serde_json::json!([1, 2]));
+        assert!(context.state.batches.is_empty());
+        reply(
+            &sender,
+            &batch,
+            serde_json::json!({"type": "error", "code": 11}),
+        );
+        run_next(&mut context);
+        assert_eq!(context.state.batches["n2"], [1, 2].into());
+        // only a gossip_batch_ok acknowledges a batch
+        flush_batch(&mut context, "n2".to_string()).unwrap();
+        let batch = sent(&outgoing);
+        reply(&sender, &batch, serde_json::json!({"type": "broadcast_ok"}));
+        run_next(&mut context);
+        assert_eq!(context.state.batches["n2"], [1, 2].into());
+        flush_batch(&mut context, "n2".to_string()).unwrap();
+        let batch = sent(&outgoing);
+        reply(
+            &sender,
+            &batch,
+            serde_json::json!({"type": "gossip_batch_ok"}),
+        );
+        run_next(&mut context);
+        assert!(context.state.batches.is_empty());
+    }
+
+    #[test]
+    fn caps_the_flush_interval_by_the_hops_to_cover() {
+        let config = BroadcastConfig {
+            mode: BroadcastMode::Batched,
+            batch_interval: std::time::Duration::from_secs(1),
+            ..BroadcastConfig::default()
+        };
+        let mut broadcast = Broadcast::new(config);
+        assert_eq!(broadcast.flush_interval(), config.batch_interval);
+        // a line of four nodes is three hops across and 5/3 hops on average
+        broadcast.topology = [
+            ("n1", vec!["n2"]),
+            ("n2", vec!["n1", "n3"]),
+            ("n3", vec!["n2", "n4"]),
+            ("n4", vec!["n3"]),
+        ]
+        .into_iter()
+        .map(|(node, neighbours)| {
+            (
+                node.to_string(),
+                neighbours.into_iter().map(str::to_string).collect(),
+            )
+        })
+        .collect();
+        assert_eq!(
+            broadcast.flush_interval(),
+            std::time::Duration::from_millis(200)
+        );
+        broadcast.config.target_max_latency = std::time::Duration::from_secs(10);
+        assert_eq!(
+            broadcast.flush_interval(),
+            std::time::Duration::from_millis(480)
+        );
+    }
+
+    #[test]
+    fn reads_the_config_from_the_environment() {
+        let eager = BroadcastConfig::from_env();
+        assert_eq!(eager.mode, BroadcastMode::Eager);
+        assert_eq!(eager.anti_entropy_interval, Some(ANTI_ENTROPY_INTERVAL));
+        std::env::set_var("BROADCAST_MODE", "batched");
+        std::env::set_var("BROADCAST_MAX_BATCH_SIZE", "8");
+        let batched = BroadcastConfig::from_env();
+        assert_eq!(batched.mode, BroadcastMode::Batched);
+        assert_eq!(batched.max_batch_size, 8);
+        assert_eq!(batched.anti_entropy_interval, None);
+        std::env::set_var("BROADCAST_ANTI_ENTROPY_INTERVAL_MS", "500");
+        assert_eq!(
+            BroadcastConfig::from_env().anti_entropy_interval,
+            Some(std::time::Duration::from_millis(500))
+        );
+        for name in [
+            "BROADCAST_MODE",
+            "BROADCAST_MAX_BATCH_SIZE",
+            "BROADCAST_ANTI_ENTROPY_INTERVAL_MS",
+        ] {
+            std::env::remove_var(name);
+        }
+    }
+}
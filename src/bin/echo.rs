@@ -0,0 +1,26 @@
+use maelstrom_challenge::{contexts, errors, nodes};
+
+#[derive(serde::Deserialize)]
+struct EchoRequest {
+    echo: String,
+}
+
+#[derive(serde::Serialize)]
+struct EchoResponse {
+    echo: String,
+}
+
+fn echo(
+    _: &mut contexts::Context,
+    request: nodes::Request<EchoRequest>,
+) -> Result<EchoResponse, errors::Error> {
+    Ok(EchoResponse {
+        echo: request.body.echo,
+    })
+}
+
+fn main() {
+    let mut node = nodes::Node::new(contexts::Context::new(()));
+    node.handle("echo", echo);
+    node.run();
+}
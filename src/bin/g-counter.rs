@@ -0,0 +1,100 @@
+use maelstrom_challenge::{contexts, nodes, storages};
+
+#[derive(serde::Deserialize)]
+struct AddRequest {
+    delta: usize,
+}
+
+#[derive(serde::Serialize)]
+struct AddResponse {}
+
+#[derive(serde::Deserialize)]
+struct ReadRequest {}
+
+#[derive(serde::Serialize)]
+struct ReadResponse {
+    value: usize,
+}
+
+type Context = contexts::Context<storages::Backend>;
+
+fn add(
+    context: &mut Context,
+    request: nodes::Request<AddRequest>,
+    responder: nodes::Responder<AddResponse>,
+) {
+    let delta = request.body.delta;
+    storages::with_storage(context, |storage, context| {
+        storage.add_counter(
+            context,
+            delta,
+            Box::new(move |context, result| {
+                responder.respond(context, result.map(|()| AddResponse {}))
+            }),
+        )
+    })
+}
+
+fn read(
+    context: &mut Context,
+    _: nodes::Request<ReadRequest>,
+    responder: nodes::Responder<ReadResponse>,
+) {
+    storages::with_storage(context, |storage, context| {
+        storage.read_counter(
+            context,
+            Box::new(move |context, result| {
+                responder.respond(context, result.map(|value| ReadResponse { value }))
+            }),
+        )
+    })
+}
+
+fn node(context: Context) -> nodes::Node<storages::Backend> {
+    let mut node = nodes::Node::new(context);
+    node.handle_deferred("add", add)
+        .handle_deferred("read", read);
+    node
+}
+
+/// The storage named by `STORAGE_BACKEND`, see [`storages::from_env`].
+fn backend() -> storages::Backend {
+    storages::Backend::new(storages::from_env())
+}
+
+fn main() {
+    node(contexts::Context::new(backend())).run();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use maelstrom_challenge::rpc;
+
+    #[test]
+    fn adds_to_the_counter_in_memory() {
+        let (rpc, sender, outgoing) = rpc::Rpc::channels();
+        std::env::set_var("STORAGE_BACKEND", "memory");
+        let backend = backend();
+        assert!(format!("{backend:?}").contains("MemoryStorage"));
+        for line in [
+            r#"{"src":"c1","dest":"n1","body":{"type":"add","msg_id":1,"delta":2}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"add","msg_id":2,"delta":3}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":3}}"#,
+        ] {
+            sender.send(line.as_bytes().to_vec()).unwrap();
+        }
+        drop(sender);
+        node(contexts::Context::with_rpc(rpc, backend)).run();
+        let bodies: Vec<serde_json::Value> = outgoing
+            .try_iter()
+            .map(|output_string| {
+                serde_json::from_str::<serde_json::Value>(&output_string).unwrap()["body"].take()
+            })
+            .collect();
+        assert_eq!(bodies[0]["type"], "add_ok");
+        assert_eq!(bodies[1]["type"], "add_ok");
+        assert_eq!(bodies[2]["type"], "read_ok");
+        assert_eq!(bodies[2]["value"], 5);
+    }
+}
@@ -0,0 +1,233 @@
+use maelstrom_challenge::{contexts, nodes, protocols, storages, stores};
+
+#[derive(serde::Deserialize)]
+struct SendRequest {
+    key: String,
+    msg: usize,
+}
+
+#[derive(serde::Serialize)]
+struct SendResponse {
+    offset: usize,
+}
+
+#[derive(serde::Deserialize)]
+struct PollRequest {
+    offsets: stores::Offsets,
+    #[serde(default)]
+    limit: Option<usize>,
+}
+
+#[derive(serde::Serialize)]
+struct PollResponse {
+    msgs: stores::LogRetrieval,
+}
+
+#[derive(serde::Deserialize)]
+struct CommitOffsetsRequest {
+    offsets: stores::Offsets,
+    #[serde(default = "protocols::default_group")]
+    group: String,
+}
+
+#[derive(serde::Serialize)]
+struct CommitOffsetsResponse {}
+
+#[derive(serde::Deserialize)]
+struct ListCommittedOffsetsRequest {
+    keys: Vec<String>,
+    #[serde(default = "protocols::default_group")]
+    group: String,
+}
+
+#[derive(serde::Serialize)]
+struct ListCommittedOffsetsResponse {
+    offsets: stores::Offsets,
+}
+
+#[derive(serde::Deserialize)]
+struct ListGroupsRequest {}
+
+#[derive(serde::Serialize)]
+struct ListGroupsResponse {
+    groups: Vec<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct GroupLagRequest {
+    group: String,
+}
+
+#[derive(serde::Serialize)]
+struct GroupLagResponse {
+    lag: stores::Offsets,
+}
+
+type Context = contexts::Context<storages::Backend>;
+
+fn send(
+    context: &mut Context,
+    request: nodes::Request<SendRequest>,
+    responder: nodes::Responder<SendResponse>,
+) {
+    let SendRequest { key, msg } = request.body;
+    storages::with_storage(context, |storage, context| {
+        storage.send(
+            context,
+            key,
+            msg,
+            Box::new(move |context, result| {
+                responder.respond(context, result.map(|offset| SendResponse { offset }))
+            }),
+        )
+    })
+}
+
+fn poll(
+    context: &mut Context,
+    request: nodes::Request<PollRequest>,
+    responder: nodes::Responder<PollResponse>,
+) {
+    let PollRequest { offsets, limit } = request.body;
+    storages::with_storage(context, |storage, context| {
+        storage.poll(
+            context,
+            offsets,
+            limit,
+            Box::new(move |context, result| {
+                responder.respond(context, result.map(|msgs| PollResponse { msgs }))
+            }),
+        )
+    })
+}
+
+fn commit_offsets(
+    context: &mut Context,
+    request: nodes::Request<CommitOffsetsRequest>,
+    responder: nodes::Responder<CommitOffsetsResponse>,
+) {
+    let CommitOffsetsRequest { offsets, group } = request.body;
+    storages::with_storage(context, |storage, context| {
+        storage.commit_offsets(
+            context,
+            group,
+            offsets,
+            Box::new(move |context, result| {
+                responder.respond(context, result.map(|()| CommitOffsetsResponse {}))
+            }),
+        )
+    })
+}
+
+fn list_committed_offsets(
+    context: &mut Context,
+    request: nodes::Request<ListCommittedOffsetsRequest>,
+    responder: nodes::Responder<ListCommittedOffsetsResponse>,
+) {
+    let ListCommittedOffsetsRequest { keys, group } = request.body;
+    storages::with_storage(context, |storage, context| {
+        storage.list_committed_offsets(
+            context,
+            group,
+            keys,
+            Box::new(move |context, result| {
+                responder.respond(
+                    context,
+                    result.map(|offsets| ListCommittedOffsetsResponse { offsets }),
+                )
+            }),
+        )
+    })
+}
+
+fn list_groups(
+    context: &mut Context,
+    _: nodes::Request<ListGroupsRequest>,
+    responder: nodes::Responder<ListGroupsResponse>,
+) {
+    storages::with_storage(context, |storage, context| {
+        storage.list_groups(
+            context,
+            Box::new(move |context, result| {
+                responder.respond(context, result.map(|groups| ListGroupsResponse { groups }))
+            }),
+        )
+    })
+}
+
+fn group_lag(
+    context: &mut Context,
+    request: nodes::Request<GroupLagRequest>,
+    responder: nodes::Responder<GroupLagResponse>,
+) {
+    storages::with_storage(context, |storage, context| {
+        storage.group_lag(
+            context,
+            request.body.group,
+            Box::new(move |context, result| {
+                responder.respond(context, result.map(|lag| GroupLagResponse { lag }))
+            }),
+        )
+    })
+}
+
+fn node(context: Context) -> nodes::Node<storages::Backend> {
+    let mut node = nodes::Node::new(context);
+    node.handle_deferred("send", send)
+        .handle_deferred("poll", poll)
+        .handle_deferred("commit_offsets", commit_offsets)
+        .handle_deferred("list_committed_offsets", list_committed_offsets)
+        .handle_deferred("list_groups", list_groups)
+        .handle_deferred("group_lag", group_lag);
+    node
+}
+
+/// The storage named by `STORAGE_BACKEND`, see [`storages::from_env`].
+fn backend() -> storages::Backend {
+    storages::Backend::new(storages::from_env())
+}
+
+fn main() {
+    node(contexts::Context::new(backend())).run();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use maelstrom_challenge::rpc;
+
+    #[test]
+    fn serves_logs_and_offsets_from_memory() {
+        let (rpc, sender, outgoing) = rpc::Rpc::channels();
+        std::env::set_var("STORAGE_BACKEND", "memory");
+        let backend = backend();
+        assert!(format!("{backend:?}").contains("MemoryStorage"));
+        for body in [
+            r#"{"type":"send","msg_id":1,"key":"k","msg":7}"#,
+            r#"{"type":"send","msg_id":2,"key":"k","msg":8}"#,
+            r#"{"type":"poll","msg_id":3,"offsets":{"k":1}}"#,
+            r#"{"type":"commit_offsets","msg_id":4,"offsets":{"k":0},"group":"g"}"#,
+            r#"{"type":"list_committed_offsets","msg_id":5,"keys":["k","x"],"group":"g"}"#,
+            r#"{"type":"list_groups","msg_id":6}"#,
+            r#"{"type":"group_lag","msg_id":7,"group":"g"}"#,
+        ] {
+            let line = format!(r#"{{"src":"c1","dest":"n1","body":{body}}}"#);
+            sender.send(line.into_bytes()).unwrap();
+        }
+        drop(sender);
+        node(contexts::Context::with_rpc(rpc, backend)).run();
+        let bodies: Vec<serde_json::Value> = outgoing
+            .try_iter()
+            .map(|output_string| {
+                serde_json::from_str::<serde_json::Value>(&output_string).unwrap()["body"].take()
+            })
+            .collect();
+        assert_eq!(bodies[0]["offset"], 0);
+        assert_eq!(bodies[1]["offset"], 1);
+        assert_eq!(bodies[2]["msgs"], serde_json::json!({"k": [[1, 8]]}));
+        assert_eq!(bodies[3]["type"], "commit_offsets_ok");
+        assert_eq!(bodies[4]["offsets"], serde_json::json!({"k": 0}));
+        assert_eq!(bodies[5]["groups"], serde_json::json!(["g"]));
+        assert_eq!(bodies[6]["lag"], serde_json::json!({"k": 1}));
+    }
+}
@@ -0,0 +1,56 @@
+use maelstrom_challenge::{contexts, errors, nodes};
+
+#[derive(serde::Deserialize)]
+struct GenerateRequest {}
+
+#[derive(serde::Serialize)]
+struct GenerateResponse {
+    id: String,
+}
+
+fn generate(
+    context: &mut contexts::Context,
+    _: nodes::Request<GenerateRequest>,
+) -> Result<GenerateResponse, errors::Error> {
+    // message ids never repeat on a node, so prefixing one with the node id is unique
+    let counter = context.read_counter_and_increment();
+    Ok(GenerateResponse {
+        id: format!("{}-{counter}", context.whoami()?),
+    })
+}
+
+fn main() {
+    let mut node = nodes::Node::new(contexts::Context::new(()));
+    node.handle("generate", generate);
+    node.run();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use maelstrom_challenge::rpc;
+
+    #[test]
+    fn prefixes_ids_with_the_node_id() {
+        let (rpc, _sender, _outgoing) = rpc::Rpc::channels();
+        let mut context = contexts::Context::with_rpc(rpc, ());
+        let generate_id = |context: &mut contexts::Context| {
+            generate(
+                context,
+                nodes::Request {
+                    src: "c1".to_string(),
+                    body: GenerateRequest {},
+                },
+            )
+            .map(|response| response.id)
+        };
+        assert!(generate_id(&mut context).is_err());
+        context
+            .initialize("n1".to_string(), vec!["n1".to_string()])
+            .unwrap();
+        let first = generate_id(&mut context).unwrap();
+        let second = generate_id(&mut context).unwrap();
+        assert!(first.starts_with("n1-"));
+        assert_ne!(first, second);
+    }
+}
@@ -1,36 +1,115 @@
+//! Per-node state shared by every handler, and clients for the maelstrom kv services.
+
 #[derive(Debug)]
 struct NodeMetadata {
     node_id: String,
     node_ids: Vec<String>,
 }
 
+/// The state of a node, owned by its event loop and lent to handlers, callbacks and timers.
 #[derive(Debug)]
-pub struct Context {
+pub struct Context<S = ()> {
     nodes: Option<NodeMetadata>,
     counter: usize,
-    messages: Vec<usize>,
+    kv_store: Option<crate::storages::TcpStorage>,
+    rpc: crate::rpc::Rpc<S>,
+    /// The state of the challenge the node serves.
+    pub state: S,
 }
 
+/// Why a node cannot be initialized.
 #[derive(Debug)]
 pub enum ContextInitializationError {
+    /// The node already got an `init` request.
     AlreadyInitialized,
 }
 
+/// Why a node does not know its cluster.
 #[derive(Debug)]
 pub enum ContextWhoamiError {
+    /// The node has not been initialized yet.
+    NotInitialized,
+}
+
+/// Why a kv request failed.
+#[derive(Debug)]
+pub enum KvError {
+    /// The node has not been initialized yet, so it has no id to send from.
     NotInitialized,
+    /// The key does not exist.
+    KeyDoesNotExist,
+    /// The value did not match the `from` of a compare-and-set.
+    PreconditionFailed,
+    /// The request got no reply.
+    Rpc(crate::rpc::RpcError),
+    /// The service replied with any other error.
+    Remote {
+        /// The maelstrom error code.
+        code: u64,
+        /// A message for humans.
+        text: String,
+    },
+    /// The reply did not decode.
+    InvalidResponse(String),
+    /// The kv-store standing in for the service failed.
+    Storage(Box<crate::storages::StorageError>),
+}
+
+impl From<crate::storages::StorageError> for KvError {
+    fn from(value: crate::storages::StorageError) -> Self {
+        match value {
+            crate::storages::StorageError::Rejected(error) => {
+                Self::from_code(error.code, error.message)
+            }
+            crate::storages::StorageError::Kv(error) => error,
+            error => Self::Storage(Box::new(error)),
+        }
+    }
 }
 
-const SERVER_ADDRESS: &str = "localhost:7999";
+impl KvError {
+    fn from_code(code: u64, text: String) -> Self {
+        match code {
+            20 => Self::KeyDoesNotExist,
+            22 => Self::PreconditionFailed,
+            code => Self::Remote { code, text },
+        }
+    }
+}
 
-impl Context {
-    pub fn new() -> Self {
+impl From<ContextWhoamiError> for KvError {
+    fn from(value: ContextWhoamiError) -> Self {
+        match value {
+            ContextWhoamiError::NotInitialized => Self::NotInitialized,
+        }
+    }
+}
+
+const KV_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+
+impl<S> Context<S> {
+    /// A context on stdio with `state`, which uses the kv-store named by
+    /// `KV_BACKEND` in place of the maelstrom kv services.
+    pub fn new(state: S) -> Self {
+        Self {
+            kv_store: crate::storages::kv_from_env(),
+            ..Self::with_rpc(crate::rpc::Rpc::stdio(), state)
+        }
+    }
+
+    /// A context that reads nothing from the environment: it talks over `rpc`
+    /// and uses the maelstrom kv services.
+    pub fn with_rpc(rpc: crate::rpc::Rpc<S>, state: S) -> Self {
         Self {
             nodes: None,
             counter: 0,
-            messages: Vec::new(),
+            kv_store: None,
+            rpc,
+            state,
         }
     }
+
+    /// Records the id of this node and of the cluster, once.
     pub fn initialize(
         &mut self,
         node_id: String,
@@ -42,88 +121,467 @@ impl Context {
         self.nodes = Some(NodeMetadata { node_id, node_ids });
         Ok(())
     }
+    /// The id of this node.
     pub fn whoami(&self) -> Result<&String, ContextWhoamiError> {
         let Some(nodes) = &self.nodes else {
             return Err(ContextWhoamiError::NotInitialized);
         };
         Ok(&nodes.node_id)
     }
+    /// Whether `node` is part of the cluster.
+    pub fn is_member(&self, node: &str) -> Result<bool, ContextWhoamiError> {
+        let Some(nodes) = &self.nodes else {
+            return Err(ContextWhoamiError::NotInitialized);
+        };
+        Ok(nodes.node_ids.iter().any(|node_id| node_id == node))
+    }
 
-    fn request(&self, inputs: String) -> String {
-        let mut socket =
-            std::net::TcpStream::connect(SERVER_ADDRESS).expect("failed to connect to server");
-        dbg!(&inputs);
-        std::io::Write::write(&mut socket, format!("{inputs}\r\n").to_string().as_bytes())
-            .expect("failed to send read request");
-        std::io::Write::flush(&mut socket).expect("failed to flush socket inputs");
-        let mut response = String::new();
-        std::io::Read::read_to_string(&mut socket, &mut response).expect("failed to read response");
-        dbg!(&response);
-        response.trim().to_string()
+    /// Waits for the next event of the event loop.
+    pub fn next_event(&mut self) -> crate::rpc::Event<S> {
+        self.rpc.next_event()
+    }
+
+    /// Writes `output` to stdout.
+    pub fn write<T>(&self, output: &crate::Output<T>)
+    where
+        T: serde::Serialize,
+    {
+        self.rpc.write(output)
+    }
+
+    fn outbound<T>(
+        &mut self,
+        dest: String,
+        typed_body: T,
+    ) -> Result<crate::Output<T>, ContextWhoamiError> {
+        let src = self.whoami()?.clone();
+        Ok(crate::Output {
+            src,
+            dest,
+            body: crate::OutputBody {
+                msg_id: self.read_counter_and_increment(),
+                in_reply_to: None,
+                typed_body,
+            },
+        })
+    }
+
+    /// Sends a request to `dest` and runs `callback` with its reply decoded as
+    /// `R`, or with an error once `timeout` elapses.
+    pub fn call<T, R>(
+        &mut self,
+        dest: String,
+        typed_body: T,
+        timeout: std::time::Duration,
+        callback: impl FnOnce(&mut Context<S>, Result<R, crate::rpc::RpcError>) + 'static,
+    ) -> Result<(), ContextWhoamiError>
+    where
+        T: serde::Serialize,
+        R: serde::de::DeserializeOwned,
+    {
+        let output = self.outbound(dest, typed_body)?;
+        self.rpc.call(
+            output,
+            timeout,
+            crate::rpc::Continuation::Callback(Box::new(move |context, reply| {
+                let reply = reply
+                    .and_then(|body| body.typed_body().map_err(crate::rpc::RpcError::Malformed));
+                callback(context, reply)
+            })),
+        );
+        Ok(())
     }
-    fn read(&self) -> usize {
-        self.request(0.to_string())
-            .parse()
-            .expect("failed to parse response")
+
+    /// Runs `timer` once after `delay`.
+    pub fn schedule(
+        &mut self,
+        delay: std::time::Duration,
+        timer: impl FnOnce(&mut Context<S>) + 'static,
+    ) {
+        self.rpc.schedule(delay, Box::new(timer))
     }
 
-    fn sync(&self, delta: usize) {
-        self.request(delta.to_string());
+    /// Runs `task` every `interval`. The next run is scheduled once the current
+    /// one finishes, so runs never overlap.
+    pub fn schedule_periodic(
+        &mut self,
+        interval: std::time::Duration,
+        mut task: impl FnMut(&mut Context<S>) + 'static,
+    ) {
+        self.schedule(interval, move |context| {
+            task(context);
+            context.schedule_periodic(interval, task)
+        })
     }
 
+    /// The next message id of this node.
     pub fn read_counter_and_increment(&mut self) -> usize {
         let result = self.counter;
         self.counter += 1;
         result
     }
 
-    pub fn read_global_counter(&self) -> usize {
-        self.read()
+    /// A client for the kv service `K`, or for the kv-store when `KV_BACKEND=kv-store`.
+    pub fn kv<K>(&mut self) -> Kv<'_, K, S>
+    where
+        K: KvService,
+    {
+        Kv {
+            context: self,
+            service: std::marker::PhantomData,
+        }
     }
+}
+
+/// A maelstrom kv service, named by the node id it answers as.
+pub trait KvService {
+    /// The node id of the service.
+    const NAME: &'static str;
+}
+
+/// The sequentially consistent `seq-kv`.
+pub struct SeqKv;
+
+impl KvService for SeqKv {
+    const NAME: &'static str = "seq-kv";
+}
 
-    pub fn add_global_counter(&self, delta: usize) {
-        self.sync(delta)
+/// The linearizable `lin-kv`.
+pub struct LinKv;
+
+impl KvService for LinKv {
+    const NAME: &'static str = "lin-kv";
+}
+
+/// The last-write-wins `lww-kv`.
+pub struct LwwKv;
+
+impl KvService for LwwKv {
+    const NAME: &'static str = "lww-kv";
+}
+
+/// A client for the kv service `K`. Each request completes with a callback on
+/// the event loop, so that the node keeps serving requests meanwhile.
+pub struct Kv<'a, K, S> {
+    context: &'a mut Context<S>,
+    service: std::marker::PhantomData<K>,
+}
+
+impl<K, S> Kv<'_, K, S>
+where
+    K: KvService,
+{
+    // callers are always called back from the event loop, never while they
+    // are still making the request
+    fn finish<T>(
+        &mut self,
+        result: Result<T, KvError>,
+        done: impl FnOnce(&mut Context<S>, Result<T, KvError>) + 'static,
+    ) where
+        T: 'static,
+    {
+        self.context
+            .schedule(std::time::Duration::ZERO, move |context| {
+                done(context, result)
+            })
+    }
+
+    // a single kv-store stands in for every kv service, so they share one key space
+    fn request(
+        &mut self,
+        operation: crate::protocols::Operation,
+        done: impl FnOnce(&mut Context<S>, Result<serde_json::Value, KvError>) + 'static,
+    ) {
+        if let Some(kv_store) = self.context.kv_store.as_mut() {
+            let result = kv_store.execute(operation).map_err(KvError::from);
+            return self.finish(result, done);
+        }
+        if self.context.whoami().is_err() {
+            return self.finish(Err(KvError::NotInitialized), done);
+        }
+        let typed_body = match operation {
+            crate::protocols::Operation::Read { key } => crate::TypedOutputBody::KvRead { key },
+            crate::protocols::Operation::Write { key, value } => {
+                crate::TypedOutputBody::KvWrite { key, value }
+            }
+            crate::protocols::Operation::Cas {
+                key,
+                from,
+                to,
+                create_if_not_exists,
+            } => crate::TypedOutputBody::KvCas {
+                key,
+                from,
+                to,
+                create_if_not_exists,
+            },
+            operation => unreachable!("{operation:?} is not a kv operation"),
+        };
+        self.context
+            .call(
+                K::NAME.to_string(),
+                typed_body,
+                KV_TIMEOUT,
+                move |context, reply| {
+                    let result = match reply {
+                        Ok(crate::TypedInputBody::KvRead { value }) => Ok(value),
+                        Ok(crate::TypedInputBody::KvWrite | crate::TypedInputBody::KvCas) => {
+                            Ok(serde_json::Value::Null)
+                        }
+                        Ok(crate::TypedInputBody::Error { code, text }) => {
+                            Err(KvError::from_code(code, text))
+                        }
+                        Err(error) => Err(KvError::Rpc(error)),
+                    };
+                    done(context, result)
+                },
+            )
+            .expect("the node is initialized");
     }
-    pub fn push_message(&mut self, message: usize) {
-        self.messages.push(message)
+
+    fn to_value<V>(value: V) -> serde_json::Value
+    where
+        V: serde::Serialize,
+    {
+        serde_json::to_value(value).expect("failed to serialize kv value")
     }
 
-    pub fn send(&self, key: String, msg: usize) -> usize {
-        let request = format!("send:{key}:{msg}");
-        self.request(request)
-            .parse()
-            .expect("failed to parse response")
+    /// Reads the value of `key`.
+    pub fn read<V>(
+        &mut self,
+        key: &str,
+        done: impl FnOnce(&mut Context<S>, Result<V, KvError>) + 'static,
+    ) where
+        V: serde::de::DeserializeOwned,
+    {
+        self.request(
+            crate::protocols::Operation::Read {
+                key: key.to_string(),
+            },
+            move |context, value| done(context, value.and_then(from_value)),
+        )
     }
 
-    fn serialize(offsets: &std::collections::BTreeMap<String, usize>) -> String {
-        offsets
+    /// Reads every key in `keys`, each with its own outcome. The reads are
+    /// pipelined on one connection when a kv-store stands in for the service,
+    /// and made one after the other otherwise.
+    pub fn read_many<V>(
+        &mut self,
+        keys: &[String],
+        done: impl FnOnce(&mut Context<S>, Result<Vec<Result<V, KvError>>, KvError>) + 'static,
+    ) where
+        V: serde::de::DeserializeOwned + 'static,
+    {
+        let Some(kv_store) = self.context.kv_store.as_mut() else {
+            if keys.is_empty() {
+                return self.finish(Ok(Vec::new()), done);
+            }
+            let keys = keys.iter().cloned().collect();
+            return read_each::<K, S, V>(self.context, keys, Vec::new(), done);
+        };
+        let operations = keys
             .iter()
-            .map(|(key, value)| format!("{key}:{value}"))
-            .collect::<Vec<String>>()
-            .join(":")
-    }
-    pub fn poll(
-        &self,
-        offsets: std::collections::BTreeMap<String, usize>,
-    ) -> std::collections::BTreeMap<String, Vec<(usize, usize)>> {
-        let arguments = Self::serialize(&offsets);
-        let request = format!("poll:{arguments}::");
-        serde_json::from_str(self.request(request).as_str()).expect("failed to parse poll response")
-    }
-
-    pub fn commit_offsets(&self, offsets: std::collections::BTreeMap<String, usize>) {
-        let arguments = Self::serialize(&offsets);
-        let request = format!("commit-offsets:{arguments}::");
-        self.request(request);
-    }
-
-    pub fn list_committed_offsets(
-        &self,
-        keys: Vec<String>,
-    ) -> std::collections::BTreeMap<String, usize> {
-        let arguments = keys.join(":");
-        let request = format!("list-committed-offsets:{arguments}::");
-        serde_json::from_str(self.request(request).as_str())
-            .expect("failed to parse list_commit_offsets response")
+            .map(|key| crate::protocols::Operation::Read { key: key.clone() })
+            .collect();
+        let result = kv_store
+            .execute_many(operations)
+            .map(|values| values.into_iter().map(|value| Ok(value?)).collect());
+        self.finish(result.map_err(KvError::from), done)
+    }
+
+    /// Sets `key` to `value`.
+    pub fn write<V>(
+        &mut self,
+        key: &str,
+        value: V,
+        done: impl FnOnce(&mut Context<S>, Result<(), KvError>) + 'static,
+    ) where
+        V: serde::Serialize,
+    {
+        self.request(
+            crate::protocols::Operation::Write {
+                key: key.to_string(),
+                value: Self::to_value(value),
+            },
+            move |context, result| done(context, result.map(|_| ())),
+        )
+    }
+
+    /// Sets `key` to `to` if it is `from`, or if it does not exist and
+    /// `create_if_not_exists` is set.
+    pub fn cas<V>(
+        &mut self,
+        key: &str,
+        from: V,
+        to: V,
+        create_if_not_exists: bool,
+        done: impl FnOnce(&mut Context<S>, Result<(), KvError>) + 'static,
+    ) where
+        V: serde::Serialize,
+    {
+        self.request(
+            crate::protocols::Operation::Cas {
+                key: key.to_string(),
+                from: Self::to_value(from),
+                to: Self::to_value(to),
+                create_if_not_exists,
+            },
+            move |context, result| done(context, result.map(|_| ())),
+        )
+    }
+}
+
+fn from_value<V>(value: serde_json::Value) -> Result<V, KvError>
+where
+    V: serde::de::DeserializeOwned,
+{
+    serde_json::from_value(value).map_err(|error| KvError::InvalidResponse(error.to_string()))
+}
+
+fn read_each<K, S, V>(
+    context: &mut Context<S>,
+    mut keys: std::collections::VecDeque<String>,
+    mut values: Vec<Result<V, KvError>>,
+    done: impl FnOnce(&mut Context<S>, Result<Vec<Result<V, KvError>>, KvError>) + 'static,
+) where
+    K: KvService,
+    V: serde::de::DeserializeOwned + 'static,
+{
+    let Some(key) = keys.pop_front() else {
+        return done(context, Ok(values));
+    };
+    context.kv::<K>().read(&key, move |context, value| {
+        values.push(value);
+        read_each::<K, S, V>(context, keys, values, done)
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    // a context on channels instead of stdio
+    pub(crate) fn context<S>(
+        state: S,
+    ) -> (
+        Context<S>,
+        std::sync::mpsc::Sender<Vec<u8>>,
+        std::sync::mpsc::Receiver<String>,
+    ) {
+        let (rpc, sender, outgoing) = crate::rpc::Rpc::channels();
+        (Context::with_rpc(rpc, state), sender, outgoing)
+    }
+
+    #[test]
+    fn initializes_only_once() {
+        let (mut context, _sender, _outgoing) = context(());
+        assert!(matches!(
+            context.whoami(),
+            Err(ContextWhoamiError::NotInitialized)
+        ));
+        context
+            .initialize("n1".to_string(), vec!["n1".to_string(), "n2".to_string()])
+            .unwrap();
+        assert!(matches!(
+            context.initialize("n2".to_string(), vec!["n2".to_string()]),
+            Err(ContextInitializationError::AlreadyInitialized)
+        ));
+        assert_eq!(context.whoami().unwrap(), "n1");
+        assert!(context.is_member("n2").unwrap());
+        assert!(!context.is_member("n3").unwrap());
+    }
+
+    // runs the next callback or timer
+    pub(crate) fn run_next<S>(context: &mut Context<S>) {
+        match context.next_event() {
+            crate::rpc::Event::Callback(callback, reply) => callback(context, reply),
+            crate::rpc::Event::Timer(timer) => timer(context),
+            _ => panic!("expect a callback or a timer"),
+        }
+    }
+
+    // reads `k` from lin-kv, which replies with `body`
+    fn read_with_reply(
+        context: &mut Context<Option<Result<u64, KvError>>>,
+        sender: &std::sync::mpsc::Sender<Vec<u8>>,
+        outgoing: &std::sync::mpsc::Receiver<String>,
+        body: &str,
+    ) -> Result<u64, KvError> {
+        context
+            .kv::<LinKv>()
+            .read("k", |context, result| context.state = Some(result));
+        reply_to(sender, &outgoing.try_recv().unwrap(), body);
+        run_next(context);
+        context.state.take().unwrap()
+    }
+
+    fn reply_to(sender: &std::sync::mpsc::Sender<Vec<u8>>, request: &str, body: &str) {
+        let request: serde_json::Value = serde_json::from_str(request).unwrap();
+        let msg_id = &request["body"]["msg_id"];
+        let line =
+            format!(r#"{{"src":"lin-kv","dest":"n1","body":{{"in_reply_to":{msg_id},{body}}}}}"#);
+        sender.send(line.into_bytes()).unwrap();
+    }
+
+    #[test]
+    fn maps_kv_error_codes() {
+        let (mut context, sender, outgoing) = context(None);
+        context
+            .kv::<LinKv>()
+            .read("k", |context, result| context.state = Some(result));
+        run_next(&mut context);
+        assert!(matches!(context.state, Some(Err(KvError::NotInitialized))));
+        context
+            .initialize("n1".to_string(), vec!["n1".to_string()])
+            .unwrap();
+        assert!(matches!(
+            read_with_reply(
+                &mut context,
+                &sender,
+                &outgoing,
+                r#""type":"error","code":20,"text":"not found""#
+            ),
+            Err(KvError::KeyDoesNotExist)
+        ));
+        assert!(matches!(
+            read_with_reply(
+                &mut context,
+                &sender,
+                &outgoing,
+                r#""type":"error","code":22"#
+            ),
+            Err(KvError::PreconditionFailed)
+        ));
+        assert!(matches!(
+            read_with_reply(
+                &mut context,
+                &sender,
+                &outgoing,
+                r#""type":"error","code":11"#
+            ),
+            Err(KvError::Remote { code: 11, .. })
+        ));
+        assert!(matches!(
+            read_with_reply(
+                &mut context,
+                &sender,
+                &outgoing,
+                r#""type":"read_ok","value":3"#
+            ),
+            Ok(3)
+        ));
+        context
+            .kv::<LinKv>()
+            .cas("k", 3, 4, false, |context, result| {
+                context.state = Some(result.map(|()| 4))
+            });
+        let request = outgoing.try_recv().unwrap();
+        assert!(request.contains(r#""type":"cas""#));
+        reply_to(&sender, &request, r#""type":"error","code":22"#);
+        run_next(&mut context);
+        assert!(matches!(
+            context.state,
+            Some(Err(KvError::PreconditionFailed))
+        ));
     }
 }
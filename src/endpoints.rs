@@ -0,0 +1,128 @@
+//! Addresses of a kv-store server, over tcp or a unix domain socket.
+
+/// The address a kv-store listens on and its clients connect to by default.
+pub const DEFAULT_ENDPOINT: &str = "localhost:7999";
+/// The variable that overrides the default endpoint. Both the kv-store and its
+/// clients read it, so that one variable points a whole cluster at a server.
+pub const ENDPOINT_VARIABLE: &str = "KV_STORE_ENDPOINT";
+
+/// Where a kv-store listens, parsed from `host:port` or `unix:<path>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    /// A tcp address.
+    Tcp(String),
+    /// The path of a unix domain socket.
+    Unix(std::path::PathBuf),
+}
+
+impl Endpoint {
+    /// Opens a connection to the server at this endpoint.
+    pub fn connect(&self) -> std::io::Result<Stream> {
+        match self {
+            Self::Tcp(address) => std::net::TcpStream::connect(address).map(Stream::Tcp),
+            Self::Unix(path) => std::os::unix::net::UnixStream::connect(path).map(Stream::Unix),
+        }
+    }
+}
+
+impl std::str::FromStr for Endpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some("") => Err(format!("missing socket path in {s:?}")),
+            Some(path) => Ok(Self::Unix(path.into())),
+            None if s.is_empty() => Err("empty endpoint".to_string()),
+            None => Ok(Self::Tcp(s.to_string())),
+        }
+    }
+}
+
+impl std::fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(address) => write!(f, "{address}"),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// A connection to or from a kv-store, over either transport.
+#[derive(Debug)]
+pub enum Stream {
+    /// A tcp connection.
+    Tcp(std::net::TcpStream),
+    /// A unix domain socket connection.
+    Unix(std::os::unix::net::UnixStream),
+}
+
+impl Stream {
+    /// Fails reads and writes that block for longer than `timeout`, or never with `None`.
+    pub fn set_timeout(&self, timeout: Option<std::time::Duration>) -> std::io::Result<()> {
+        match self {
+            Self::Tcp(stream) => {
+                stream.set_read_timeout(timeout)?;
+                stream.set_write_timeout(timeout)
+            }
+            Self::Unix(stream) => {
+                stream.set_read_timeout(timeout)?;
+                stream.set_write_timeout(timeout)
+            }
+        }
+    }
+
+    /// Another handle to the same connection, e.g. to read and write from different threads.
+    pub fn try_clone(&self) -> std::io::Result<Self> {
+        match self {
+            Self::Tcp(stream) => stream.try_clone().map(Self::Tcp),
+            Self::Unix(stream) => stream.try_clone().map(Self::Unix),
+        }
+    }
+}
+
+impl std::io::Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.read(buf),
+            Self::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl std::io::Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.write(buf),
+            Self::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.flush(),
+            Self::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_tcp_and_unix_endpoints() {
+        assert_eq!(
+            "localhost:7999".parse(),
+            Ok(Endpoint::Tcp("localhost:7999".to_string()))
+        );
+        assert_eq!(
+            "unix:/tmp/kv.sock".parse(),
+            Ok(Endpoint::Unix("/tmp/kv.sock".into()))
+        );
+        assert!("unix:".parse::<Endpoint>().is_err());
+        assert!("".parse::<Endpoint>().is_err());
+        for endpoint in ["localhost:7999", "unix:/tmp/kv.sock"] {
+            assert_eq!(endpoint.parse::<Endpoint>().unwrap().to_string(), endpoint);
+        }
+    }
+}
@@ -0,0 +1,182 @@
+//! Maelstrom error codes and the errors handlers reply with.
+
+/// The error codes of the maelstrom protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The request timed out, it may or may not have taken effect.
+    Timeout = 0,
+    /// The destination node does not exist.
+    NodeNotFound = 1,
+    /// The request type is not supported.
+    NotSupported = 10,
+    /// The request cannot be served right now, a retry may succeed.
+    TemporarilyUnavailable = 11,
+    /// The request is invalid.
+    MalformedRequest = 12,
+    /// The node failed, the request may or may not have taken effect.
+    Crash = 13,
+    /// The request failed without taking effect.
+    Abort = 14,
+    /// The key does not exist.
+    KeyDoesNotExist = 20,
+    /// The key already exists.
+    KeyAlreadyExists = 21,
+    /// The current value did not match, e.g. of a compare-and-set.
+    PreconditionFailed = 22,
+    /// The transaction conflicted with another one.
+    TxnConflict = 30,
+}
+
+impl ErrorCode {
+    /// The error code with the numeric value `code`, if there is one.
+    pub fn from_code(code: u64) -> Option<Self> {
+        [
+            Self::Timeout,
+            Self::NodeNotFound,
+            Self::NotSupported,
+            Self::TemporarilyUnavailable,
+            Self::MalformedRequest,
+            Self::Crash,
+            Self::Abort,
+            Self::KeyDoesNotExist,
+            Self::KeyAlreadyExists,
+            Self::PreconditionFailed,
+            Self::TxnConflict,
+        ]
+        .into_iter()
+        .find(|error_code| *error_code as u64 == code)
+    }
+}
+
+impl serde::Serialize for ErrorCode {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_u64(*self as u64)
+    }
+}
+
+/// An error a handler replies with.
+#[derive(Debug)]
+pub struct Error {
+    /// The code clients act on.
+    pub code: ErrorCode,
+    /// A message for humans.
+    pub text: String,
+}
+
+impl Error {
+    /// An error with `code` and `text`.
+    pub fn new(code: ErrorCode, text: impl Into<String>) -> Self {
+        Self {
+            code,
+            text: text.into(),
+        }
+    }
+    /// A [`ErrorCode::MalformedRequest`] error.
+    pub fn malformed_request(text: impl Into<String>) -> Self {
+        Self::new(ErrorCode::MalformedRequest, text)
+    }
+    /// A [`ErrorCode::NotSupported`] error.
+    pub fn not_supported(text: impl Into<String>) -> Self {
+        Self::new(ErrorCode::NotSupported, text)
+    }
+}
+
+impl From<crate::contexts::ContextInitializationError> for Error {
+    fn from(value: crate::contexts::ContextInitializationError) -> Self {
+        match value {
+            crate::contexts::ContextInitializationError::AlreadyInitialized => Self::new(
+                ErrorCode::PreconditionFailed,
+                "node has already been initialized",
+            ),
+        }
+    }
+}
+
+impl From<crate::contexts::ContextWhoamiError> for Error {
+    fn from(value: crate::contexts::ContextWhoamiError) -> Self {
+        match value {
+            crate::contexts::ContextWhoamiError::NotInitialized => Self::new(
+                ErrorCode::TemporarilyUnavailable,
+                "node has not been initialized yet",
+            ),
+        }
+    }
+}
+
+impl From<crate::storages::StorageError> for Error {
+    fn from(value: crate::storages::StorageError) -> Self {
+        match value {
+            crate::storages::StorageError::Unavailable(error) => Self::new(
+                ErrorCode::TemporarilyUnavailable,
+                format!("storage unavailable: {error}"),
+            ),
+            // the request may or may not have reached the kv-store, like a timed out rpc
+            crate::storages::StorageError::Io(error)
+                if matches!(
+                    error.kind(),
+                    std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock
+                ) =>
+            {
+                Self::new(ErrorCode::Timeout, format!("storage timed out: {error}"))
+            }
+            crate::storages::StorageError::Io(error) => {
+                Self::new(ErrorCode::Crash, format!("storage i/o failed: {error}"))
+            }
+            crate::storages::StorageError::InvalidResponse(response) => Self::new(
+                ErrorCode::Crash,
+                format!("storage returned an invalid response: {response:?}"),
+            ),
+            crate::storages::StorageError::Rejected(error) => Self::new(
+                ErrorCode::from_code(error.code).unwrap_or(ErrorCode::Abort),
+                format!("storage rejected the request: {}", error.message),
+            ),
+            crate::storages::StorageError::Unsupported(text) => {
+                Self::new(ErrorCode::NotSupported, text)
+            }
+            crate::storages::StorageError::Kv(error) => error.into(),
+        }
+    }
+}
+
+impl From<crate::rpc::RpcError> for Error {
+    fn from(value: crate::rpc::RpcError) -> Self {
+        match value {
+            crate::rpc::RpcError::Timeout => Self::new(ErrorCode::Timeout, "request timed out"),
+            crate::rpc::RpcError::Disconnected => {
+                Self::new(ErrorCode::Crash, "node is shutting down")
+            }
+            crate::rpc::RpcError::Malformed(error) => Self::new(
+                ErrorCode::Crash,
+                format!("received a malformed reply: {error}"),
+            ),
+        }
+    }
+}
+
+impl From<crate::contexts::KvError> for Error {
+    fn from(value: crate::contexts::KvError) -> Self {
+        match value {
+            crate::contexts::KvError::NotInitialized => {
+                crate::contexts::ContextWhoamiError::NotInitialized.into()
+            }
+            crate::contexts::KvError::KeyDoesNotExist => {
+                Self::new(ErrorCode::KeyDoesNotExist, "key does not exist")
+            }
+            crate::contexts::KvError::PreconditionFailed => {
+                Self::new(ErrorCode::PreconditionFailed, "precondition failed")
+            }
+            crate::contexts::KvError::Rpc(error) => error.into(),
+            crate::contexts::KvError::Remote { code, text } => {
+                Self::new(ErrorCode::from_code(code).unwrap_or(ErrorCode::Crash), text)
+            }
+            crate::contexts::KvError::InvalidResponse(text) => Self::new(
+                ErrorCode::Crash,
+                format!("kv service returned an invalid response: {text}"),
+            ),
+            crate::contexts::KvError::Storage(error) => (*error).into(),
+        }
+    }
+}
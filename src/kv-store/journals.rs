@@ -0,0 +1,178 @@
+use crate::protocols::Operation;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    Always,
+    Interval(std::time::Duration),
+    Never,
+}
+
+impl std::str::FromStr for SyncPolicy {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            milliseconds => Ok(Self::Interval(std::time::Duration::from_millis(
+                milliseconds.trim_end_matches("ms").parse()?,
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct Record {
+    // records written before timestamps were journaled have none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    #[serde(flatten)]
+    pub operation: Operation,
+}
+
+// append-only log of the mutating operations, one json record per line
+#[derive(Debug)]
+pub struct Journal {
+    file: std::sync::Mutex<std::fs::File>,
+    records: std::sync::atomic::AtomicUsize,
+    policy: SyncPolicy,
+}
+
+impl Journal {
+    pub fn open(
+        path: &std::path::Path,
+        policy: SyncPolicy,
+    ) -> std::io::Result<(Self, Vec<Record>)> {
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+        let mut content = Vec::new();
+        std::io::Read::read_to_end(&mut file, &mut content)?;
+
+        let mut records = Vec::new();
+        let mut valid_length = 0;
+        for record in content.split_inclusive(|byte| *byte == b'\n') {
+            // a crash can leave a torn record at the tail, which was never acknowledged
+            let Some(parsed_record) = record
+                .strip_suffix(b"\n")
+                .and_then(|record| serde_json::from_slice(record).ok())
+            else {
+                eprintln!(
+                    "discarding {} bytes of torn journal records",
+                    content.len() - valid_length
+                );
+                break;
+            };
+            records.push(parsed_record);
+            valid_length += record.len();
+        }
+        file.set_len(valid_length as u64)?;
+
+        if let SyncPolicy::Interval(interval) = policy {
+            let file = file.try_clone()?;
+            std::thread::spawn(move || loop {
+                std::thread::sleep(interval);
+                if let Err(error) = file.sync_data() {
+                    eprintln!("failed to sync journal: {error}");
+                }
+            });
+        }
+        Ok((
+            Self {
+                file: std::sync::Mutex::new(file),
+                records: records.len().into(),
+                policy,
+            },
+            records,
+        ))
+    }
+
+    pub fn append(&self, record: &Record) -> std::io::Result<()> {
+        let mut record = serde_json::to_vec(record).expect("failed to serialize");
+        record.push(b'\n');
+        let mut file = self.file.lock().expect("journal lock poisoned");
+        let length = file.metadata()?.len();
+        let appended =
+            std::io::Write::write_all(&mut *file, &record).and_then(|_| match self.policy {
+                SyncPolicy::Always => file.sync_data(),
+                SyncPolicy::Interval(_) | SyncPolicy::Never => Ok(()),
+            });
+        if let Err(error) = appended {
+            // a failed record is never applied, so it must not be replayed either
+            if let Err(error) = file.set_len(length) {
+                eprintln!("failed to roll back the journal: {error}");
+            }
+            return Err(error);
+        }
+        self.records
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        Ok(())
+    }
+
+    pub fn sync(&self) -> std::io::Result<()> {
+        self.file.lock().expect("journal lock poisoned").sync_data()
+    }
+
+    pub fn records(&self) -> usize {
+        self.records.load(std::sync::atomic::Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stores::Offsets;
+
+    #[test]
+    fn parses_sync_policies() {
+        assert_eq!("always".parse(), Ok(SyncPolicy::Always));
+        assert_eq!("never".parse(), Ok(SyncPolicy::Never));
+        for interval in ["100", "100ms"] {
+            assert_eq!(
+                interval.parse(),
+                Ok(SyncPolicy::Interval(std::time::Duration::from_millis(100)))
+            );
+        }
+        assert!("sometimes".parse::<SyncPolicy>().is_err());
+    }
+
+    #[test]
+    fn recovers_state_from_the_journal() {
+        let path = std::env::temp_dir().join(format!("kv-store-{}.journal", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        {
+            let (journal, operations) = Journal::open(&path, SyncPolicy::Always).unwrap();
+            let store =
+                crate::shards::Shards::recover(4, None, Some((journal, operations))).unwrap();
+            store.send("k".to_string(), 7).unwrap();
+            store.send("k".to_string(), 8).unwrap();
+            store
+                .commit_offsets(
+                    crate::stores::DEFAULT_GROUP.to_string(),
+                    Offsets::from([("k".to_string(), 1)]),
+                )
+                .unwrap();
+            store.counter_add("c".to_string(), 3).unwrap();
+        }
+        // a torn record left behind by a crash is dropped on recovery
+        std::io::Write::write_all(
+            &mut std::fs::OpenOptions::new()
+                .append(true)
+                .open(&path)
+                .unwrap(),
+            br#"{"op":"send","ar"#,
+        )
+        .unwrap();
+        let (journal, operations) = Journal::open(&path, SyncPolicy::Never).unwrap();
+        let store = crate::shards::Shards::recover(4, None, Some((journal, operations))).unwrap();
+        assert_eq!(store.send("k".to_string(), 9).unwrap(), 2);
+        assert_eq!(
+            store.list_committed_offsets(crate::stores::DEFAULT_GROUP, vec!["k".to_string()]),
+            Offsets::from([("k".to_string(), 1)])
+        );
+        assert_eq!(store.counter_read("c"), 3);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,32 @@
+use crate::endpoints::{Endpoint, Stream};
+
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(std::net::TcpListener),
+    Unix(std::os::unix::net::UnixListener),
+}
+
+impl Listener {
+    pub fn bind(endpoint: &Endpoint) -> std::io::Result<Self> {
+        match endpoint {
+            Endpoint::Tcp(address) => std::net::TcpListener::bind(address).map(Self::Tcp),
+            Endpoint::Unix(path) => {
+                // a socket file left behind by a server that is gone would fail the bind
+                if path.exists() {
+                    if endpoint.connect().is_ok() {
+                        return Err(std::io::ErrorKind::AddrInUse.into());
+                    }
+                    std::fs::remove_file(path)?;
+                }
+                std::os::unix::net::UnixListener::bind(path).map(Self::Unix)
+            }
+        }
+    }
+
+    pub fn accept(&self) -> std::io::Result<Stream> {
+        match self {
+            Self::Tcp(listener) => listener.accept().map(|(stream, _)| Stream::Tcp(stream)),
+            Self::Unix(listener) => listener.accept().map(|(stream, _)| Stream::Unix(stream)),
+        }
+    }
+}
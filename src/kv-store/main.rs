@@ -1,165 +1,523 @@
-type Offsets = std::collections::BTreeMap<String, usize>;
-type Logs = std::collections::BTreeMap<String, Vec<usize>>;
-type LogRetrieval = std::collections::BTreeMap<String, Vec<(usize, usize)>>;
-
-enum Request {
-    Send { key: String, msg: usize },
-    Poll { offsets: Offsets },
-    CommitOffsets { offsets: Offsets },
-    ListCommittedOffsets { keys: Vec<String> },
-}
+mod journals;
+mod listeners;
+mod parsers;
+mod retentions;
+mod shards;
+mod snapshots;
+mod workers;
 
-#[derive(Debug)]
-enum ParseRequestError {
-    InvalidFormat(String),
-    InvalidCommand(String),
-    InvalidNumber(std::num::ParseIntError),
-    EmptyString,
-}
+use maelstrom_challenge::{endpoints, protocols, stores};
+use protocols::Operation;
 
-impl From<std::num::ParseIntError> for ParseRequestError {
-    fn from(value: std::num::ParseIntError) -> Self {
-        Self::InvalidNumber(value)
+fn execute(
+    store: &shards::Shards,
+    operation: Operation,
+) -> Result<serde_json::Value, protocols::Error> {
+    match operation {
+        Operation::Send { key, msg } => Ok(store.send(key, msg)?.into()),
+        Operation::Poll { offsets, limit } => {
+            Ok(serde_json::to_value(store.poll(offsets, limit)?).expect("failed to serialize"))
+        }
+        Operation::CommitOffsets { offsets, group } => {
+            store.commit_offsets(group, offsets)?;
+            Ok(serde_json::Value::Null)
+        }
+        Operation::ListCommittedOffsets { keys, group } => Ok(serde_json::to_value(
+            store.list_committed_offsets(group.as_str(), keys),
+        )
+        .expect("failed to serialize")),
+        Operation::ListGroups => Ok(store.groups().into()),
+        Operation::GroupLag { group } => {
+            Ok(serde_json::to_value(store.lag(group.as_str())).expect("failed to serialize"))
+        }
+        Operation::CounterRead { key } => Ok(store.counter_read(&key).into()),
+        Operation::CounterAdd { key, delta } => Ok(store.counter_add(key, delta)?.into()),
+        Operation::SetRetention { key, policy } => {
+            store.set_retention(key, policy)?;
+            Ok(serde_json::Value::Null)
+        }
+        Operation::Truncate { key, offset } => {
+            store.truncate(key, offset)?;
+            Ok(serde_json::Value::Null)
+        }
+        Operation::Read { key } => store.read(key.as_str()),
+        Operation::Write { key, value } => {
+            store.write(key, value)?;
+            Ok(serde_json::Value::Null)
+        }
+        Operation::Cas {
+            key,
+            from,
+            to,
+            create_if_not_exists,
+        } => store
+            .cas(key, from, to, create_if_not_exists)
+            .map(|_| serde_json::Value::Null),
+        Operation::Snapshot => store
+            .snapshot()
+            .map(serde_json::Value::from)
+            .map_err(|error| {
+                protocols::Error::new(protocols::ErrorKind::SnapshotFailed, error.to_string())
+            }),
     }
 }
-fn take_first_token(string: &str) -> Result<(&str, &str), ParseRequestError> {
-    let Some((command, remain)) = string.split_once(':') else {
-        return Err(ParseRequestError::InvalidFormat(string.to_string()));
+
+fn respond(store: &shards::Shards, request_string: &str, legacy: bool) -> String {
+    if legacy && !request_string.starts_with('{') {
+        return match parsers::parse(request_string).map(|operation| execute(store, operation)) {
+            Ok(Ok(serde_json::Value::Null)) => "".to_string(),
+            Ok(Ok(value)) => value.to_string(),
+            Ok(Err(error)) => {
+                eprintln!("failed to execute request: {}", error.message);
+                "".to_string()
+            }
+            Err(error) => {
+                eprintln!("failed to parse request: {error}");
+                "".to_string()
+            }
+        };
+    }
+    let response = match serde_json::from_str::<protocols::Request>(request_string) {
+        Ok(request) if request.version != protocols::VERSION => protocols::Response {
+            version: protocols::VERSION,
+            id: request.id,
+            outcome: protocols::Outcome::Error(protocols::Error::new(
+                protocols::ErrorKind::UnsupportedVersion,
+                format!(
+                    "expect protocol version {}, got {}",
+                    protocols::VERSION,
+                    request.version
+                ),
+            )),
+        },
+        Ok(request) => protocols::Response {
+            version: protocols::VERSION,
+            id: request.id,
+            outcome: match execute(store, request.operation) {
+                Ok(value) => protocols::Outcome::Result(value),
+                Err(error) => protocols::Outcome::Error(error),
+            },
+        },
+        Err(error) => protocols::Response {
+            version: protocols::VERSION,
+            id: serde_json::from_str::<serde_json::Value>(request_string)
+                .ok()
+                .and_then(|value| value.get("id")?.as_u64())
+                .unwrap_or_default(),
+            outcome: protocols::Outcome::Error(protocols::Error::new(
+                protocols::ErrorKind::MalformedRequest,
+                error.to_string(),
+            )),
+        },
     };
-    Ok((command, remain))
+    serde_json::to_string(&response).expect("failed to serialize")
 }
 
-fn parse_offsets(mut remain: &str) -> Result<Offsets, ParseRequestError> {
-    let mut offsets = Offsets::new();
+fn serve(
+    stream: endpoints::Stream,
+    store: &std::sync::Arc<shards::Shards>,
+    workers: &workers::Workers,
+    legacy: bool,
+) -> std::io::Result<()> {
+    let writer = std::sync::Arc::new(std::sync::Mutex::new(stream.try_clone()?));
+    let mut buffer_reader = std::io::BufReader::new(stream);
     loop {
-        let (key, new_remain) = take_first_token(remain)?;
-        remain = new_remain;
-        if key.is_empty() {
-            break;
+        let mut request_string = String::new();
+        if std::io::BufRead::read_line(&mut buffer_reader, &mut request_string)? == 0 {
+            return Ok(());
         }
-        let (offset_string, new_remain) = take_first_token(remain)?;
-        remain = new_remain;
-        let offset = offset_string.parse()?;
-        offsets.insert(key.to_string(), offset);
-    }
-    Ok(offsets)
-}
-impl std::str::FromStr for Request {
-    type Err = ParseRequestError;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let (command, mut remain) = take_first_token(s)?;
-        match command {
-            "send" => {
-                let (key, msg) = take_first_token(remain)?;
-                Ok(Self::Send {
-                    key: key.to_string(),
-                    msg: msg.parse()?,
-                })
-            }
-            "poll" => {
-                let offsets = parse_offsets(remain)?;
-                Ok(Self::Poll { offsets })
-            }
-            "commit-offsets" => {
-                let offsets = parse_offsets(remain)?;
-                Ok(Self::CommitOffsets { offsets })
+        let request_string = request_string.trim().to_string();
+        // legacy clients read until the connection closes, which happens once both
+        // this reader and the job responding to the line have dropped their handles
+        let last = legacy && !request_string.starts_with('{');
+        let store = store.clone();
+        let writer = writer.clone();
+        workers.submit(move || {
+            let response = respond(&store, &request_string, legacy);
+            let mut writer = writer.lock().expect("writer lock poisoned");
+            if let Err(error) =
+                std::io::Write::write_all(&mut *writer, format!("{response}\n").as_bytes())
+            {
+                eprintln!("failed to respond: {error}");
             }
-            "list-committed-offsets" => {
-                let mut keys = Vec::new();
-                loop {
-                    let (key, new_remain) = take_first_token(remain)?;
-                    remain = new_remain;
-                    if key.is_empty() {
-                        break;
-                    }
-                    keys.push(key.to_string())
-                }
-                Ok(Self::ListCommittedOffsets { keys })
-            }
-            command => Err(ParseRequestError::InvalidCommand(command.to_string())),
+        });
+        if last {
+            return Ok(());
         }
     }
 }
 
-fn main() {
-    let listener =
-        std::net::TcpListener::bind("localhost:7999").expect("failed to listen to port 7999");
-
-    let mut logs = Logs::new();
-    let mut offset_registry = Offsets::new();
-    listener.incoming().for_each(|request| {
-        let mut stream = request.expect("failed to acquire stream");
-        let mut buffer_reader = std::io::BufReader::new(&mut stream);
-        let mut request_string = String::new();
-        std::io::BufRead::read_line(&mut buffer_reader, &mut request_string)
-            .expect("failed to read inputs");
-        dbg!(&request_string);
-        // dbg!(&logs, &offset_registry);
-        let response: String = match <Request as std::str::FromStr>::from_str(request_string.trim())
-        {
-            Ok(request) => match request {
-                Request::Send { key, msg } => {
-                    let log = logs.entry(key).or_default();
-                    let offset = log.len();
-                    log.push(msg);
-                    offset.to_string()
+const SHARD_COUNT: usize = 64;
+
+#[derive(Debug)]
+struct Config {
+    legacy: bool,
+    workers: usize,
+    journal: Option<std::path::PathBuf>,
+    sync_policy: journals::SyncPolicy,
+    snapshots: Option<std::path::PathBuf>,
+    snapshot_interval: Option<std::time::Duration>,
+    poll_limit: usize,
+    retention: stores::RetentionPolicy,
+    retention_interval: std::time::Duration,
+    endpoint: endpoints::Endpoint,
+}
+
+impl Config {
+    fn from_args() -> Self {
+        let mut config = Self {
+            legacy: false,
+            workers: std::thread::available_parallelism()
+                .map(std::num::NonZeroUsize::get)
+                .unwrap_or(1),
+            journal: None,
+            sync_policy: journals::SyncPolicy::Interval(std::time::Duration::from_millis(100)),
+            snapshots: None,
+            snapshot_interval: None,
+            poll_limit: stores::DEFAULT_POLL_LIMIT,
+            retention: stores::RetentionPolicy::default(),
+            retention_interval: std::time::Duration::from_secs(1),
+            endpoint: std::env::var(endpoints::ENDPOINT_VARIABLE)
+                .as_deref()
+                .unwrap_or(endpoints::DEFAULT_ENDPOINT)
+                .parse()
+                .unwrap_or_else(|error| {
+                    panic!("invalid {}: {error}", endpoints::ENDPOINT_VARIABLE)
+                }),
+        };
+        let mut arguments = std::env::args().skip(1);
+        while let Some(argument) = arguments.next() {
+            match argument.as_str() {
+                "--legacy-protocol" => config.legacy = true,
+                "--workers" => {
+                    config.workers = arguments
+                        .next()
+                        .and_then(|workers| workers.parse().ok())
+                        .filter(|workers| *workers > 0)
+                        .expect("--workers expects a positive number")
                 }
-                Request::Poll { offsets } => {
-                    let mut retrieved = LogRetrieval::new();
-                    offsets.into_iter().for_each(|(key, offset)| {
-                        retrieved.insert(key.clone(), {
-                            match offset.cmp(
-                                &offset_registry
-                                    .get(&key)
-                                    .map(|committed_offset| committed_offset + 1)
-                                    .unwrap_or_default(),
-                            ) {
-                                std::cmp::Ordering::Equal | std::cmp::Ordering::Less => {
-                                    logs.entry(key).or_default()[offset..]
-                                        .iter()
-                                        .take(1)
-                                        .enumerate()
-                                        .map(|(index, message)| (offset + index, *message))
-                                        .collect()
-                                }
-                                std::cmp::Ordering::Greater => Vec::new(),
-                            }
-                        });
-                    });
-                    serde_json::to_string(&retrieved).expect("failed to serialize")
+                "--journal" => {
+                    config.journal =
+                        Some(arguments.next().expect("--journal expects a path").into())
                 }
-                Request::CommitOffsets { offsets } => {
-                    offsets.into_iter().for_each(|(key, incoming_offset)| {
-                        offset_registry
-                            .entry(key)
-                            .and_modify(|committed_offset| {
-                                *committed_offset = incoming_offset.max(*committed_offset)
-                            })
-                            .or_insert(incoming_offset);
-                    });
-                    "".to_string()
+                "--fsync" => {
+                    config.sync_policy = arguments
+                        .next()
+                        .and_then(|policy| policy.parse().ok())
+                        .expect("--fsync expects always, never or an interval in milliseconds")
                 }
-                Request::ListCommittedOffsets { keys } => {
-                    let retrieved: Offsets = keys
-                        .into_iter()
-                        .filter(|key| offset_registry.get(key).is_some())
-                        .map(|key| (key.clone(), *offset_registry.get(&key).unwrap()))
-                        .collect();
-                    serde_json::to_string(&retrieved).expect("failed to serialize")
+                "--snapshots" => {
+                    config.snapshots = Some(
+                        arguments
+                            .next()
+                            .expect("--snapshots expects a directory")
+                            .into(),
+                    )
                 }
-            },
-            Err(error) => {
-                dbg!(error);
-                "".to_string()
+                "--snapshot-interval-ms" => {
+                    config.snapshot_interval = Some(
+                        arguments
+                            .next()
+                            .and_then(|interval| interval.parse().ok())
+                            .map(std::time::Duration::from_millis)
+                            .expect("--snapshot-interval-ms expects a number"),
+                    )
+                }
+                "--poll-limit" => {
+                    config.poll_limit = arguments
+                        .next()
+                        .and_then(|limit| limit.parse().ok())
+                        .filter(|limit| *limit > 0)
+                        .expect("--poll-limit expects a positive number")
+                }
+                "--retain-messages" => {
+                    config.retention.max_messages = Some(
+                        arguments
+                            .next()
+                            .and_then(|count| count.parse().ok())
+                            .expect("--retain-messages expects a number"),
+                    )
+                }
+                "--retain-ms" => {
+                    config.retention.max_age_ms = Some(
+                        arguments
+                            .next()
+                            .and_then(|age| age.parse().ok())
+                            .expect("--retain-ms expects a number"),
+                    )
+                }
+                "--truncate-committed" => config.retention.below_committed = true,
+                "--retention-interval-ms" => {
+                    config.retention_interval = arguments
+                        .next()
+                        .and_then(|interval| interval.parse().ok())
+                        .map(std::time::Duration::from_millis)
+                        .expect("--retention-interval-ms expects a number")
+                }
+                "--endpoint" => {
+                    config.endpoint = arguments
+                        .next()
+                        .expect("--endpoint expects host:port or unix:path")
+                        .parse()
+                        .unwrap_or_else(|error| panic!("invalid --endpoint: {error}"))
+                }
+                argument => panic!("unknown argument: {argument}"),
             }
-        };
-        dbg!(&response);
-        std::io::Write::write(&mut stream, format!("{response}\n").as_bytes())
-            .expect("failed to respond to client");
-        println!("response sent");
-    })
+        }
+        config
+    }
+}
+
+fn main() {
+    let config = Config::from_args();
+    let listener = listeners::Listener::bind(&config.endpoint)
+        .unwrap_or_else(|error| panic!("failed to listen on {}: {error}", config.endpoint));
+
+    let snapshots = config.snapshots.as_ref().map(|directory| {
+        let (snapshots, snapshot) =
+            snapshots::Snapshots::open(directory).expect("failed to open the snapshots");
+        (snapshots, snapshot.unwrap_or_default())
+    });
+    let journal = config.journal.as_ref().map(|path| {
+        journals::Journal::open(path, config.sync_policy).expect("failed to open the journal")
+    });
+    let store = shards::Shards::recover(SHARD_COUNT, snapshots, journal)
+        .expect("failed to recover the store")
+        .with_poll_limit(config.poll_limit)
+        .with_retention(config.retention);
+    let store = std::sync::Arc::new(store);
+
+    if let (Some(_), Some(interval)) = (&config.snapshots, config.snapshot_interval) {
+        let store = store.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            if let Err(error) = store.snapshot() {
+                eprintln!("failed to take a snapshot: {error}");
+            }
+        });
+    }
+    {
+        let store = store.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(config.retention_interval);
+            store.enforce_retentions();
+        });
+    }
+    let workers = std::sync::Arc::new(workers::Workers::new(config.workers));
+    loop {
+        let stream = listener.accept().expect("failed to acquire stream");
+        let store = store.clone();
+        let workers = workers.clone();
+        std::thread::spawn(move || {
+            if let Err(error) = serve(stream, &store, &workers, config.legacy) {
+                eprintln!("connection closed: {error}");
+            }
+        });
+    }
 }
 
 #[cfg(test)]
-mod test {}
+mod test {
+    use super::*;
+    use crate::stores::Offsets;
+    use maelstrom_challenge::storages;
+
+    #[test]
+    fn responds_to_framed_json_requests() {
+        let store = shards::Shards::new(4);
+        let response = respond(
+            &store,
+            r#"{"version":1,"id":7,"op":"send","args":{"key":"a:b","msg":3}}"#,
+            false,
+        );
+        assert_eq!(response, r#"{"version":1,"id":7,"result":0}"#);
+        let response = respond(
+            &store,
+            r#"{"version":1,"id":8,"op":"poll","args":{"offsets":{"a:b":0}}}"#,
+            false,
+        );
+        assert_eq!(response, r#"{"version":1,"id":8,"result":{"a:b":[[0,3]]}}"#);
+    }
+
+    #[test]
+    fn rejects_unsupported_requests() {
+        let store = shards::Shards::new(4);
+        let response = respond(
+            &store,
+            r#"{"version":2,"id":1,"op":"counter-read","args":{"key":"c"}}"#,
+            false,
+        );
+        assert!(response.contains(r#""kind":"unsupported-version""#));
+        let response = respond(&store, "send:a:1", false);
+        assert!(response.contains(r#""kind":"malformed-request""#));
+        assert_eq!(respond(&store, "send:a:1", true), "0");
+    }
+
+    #[test]
+    fn serves_pipelined_requests_on_one_connection() {
+        let endpoint = endpoints::Endpoint::Unix(
+            std::env::temp_dir().join(format!("kv-store-{}.sock", std::process::id())),
+        );
+        let listener = listeners::Listener::bind(&endpoint).unwrap();
+        std::thread::spawn(move || {
+            let store = std::sync::Arc::new(shards::Shards::new(4));
+            // a single worker keeps the responses in request order
+            let workers = workers::Workers::new(1);
+            let stream = listener.accept().unwrap();
+            serve(stream, &store, &workers, false).unwrap();
+        });
+        let mut stream = endpoint.connect().unwrap();
+        std::io::Write::write_all(
+            &mut stream,
+            concat!(
+                r#"{"version":1,"id":1,"op":"counter-add","args":{"key":"c","delta":2}}"#,
+                "\n",
+                r#"{"version":1,"id":2,"op":"counter-add","args":{"key":"c","delta":3}}"#,
+                "\n",
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+        let mut reader = std::io::BufReader::new(stream);
+        let mut responses = Vec::new();
+        for _ in 0..2 {
+            let mut response = String::new();
+            std::io::BufRead::read_line(&mut reader, &mut response).unwrap();
+            responses.push(response.trim().to_string());
+        }
+        assert_eq!(
+            responses,
+            [
+                r#"{"version":1,"id":1,"result":2}"#,
+                r#"{"version":1,"id":2,"result":5}"#
+            ]
+        );
+    }
+
+    #[test]
+    fn pipelines_client_operations_over_a_unix_socket() {
+        let endpoint = endpoints::Endpoint::Unix(
+            std::env::temp_dir().join(format!("kv-store-{}-client.sock", std::process::id())),
+        );
+        let listener = listeners::Listener::bind(&endpoint).unwrap();
+        std::thread::spawn(move || {
+            let store = std::sync::Arc::new(shards::Shards::new(4));
+            // several workers answer out of order, the client matches responses by id
+            let workers = workers::Workers::new(4);
+            loop {
+                let stream = listener.accept().unwrap();
+                serve(stream, &store, &workers, false).unwrap();
+            }
+        });
+        let mut client = storages::TcpStorage::new(endpoint, storages::WireProtocol::Json);
+        let offsets = client
+            .execute_many::<usize>(
+                (0..10)
+                    .map(|msg| Operation::Send {
+                        key: format!("k{}", msg % 2),
+                        msg,
+                    })
+                    .collect(),
+            )
+            .unwrap();
+        let offsets = offsets.into_iter().map(Result::unwrap).collect::<Vec<_>>();
+        let mut sorted_offsets = offsets.clone();
+        sorted_offsets.sort();
+        assert_eq!(sorted_offsets, [0, 0, 1, 1, 2, 2, 3, 3, 4, 4]);
+        let values = client
+            .execute_many::<serde_json::Value>(vec![
+                Operation::Write {
+                    key: "x".to_string(),
+                    value: 1.into(),
+                },
+                Operation::Read {
+                    key: "y".to_string(),
+                },
+            ])
+            .unwrap();
+        assert!(matches!(values[0], Ok(serde_json::Value::Null)));
+        assert!(matches!(
+            &values[1],
+            Err(storages::StorageError::Rejected(error)) if error.code == 20
+        ));
+        // the workers append to a key in any order, each message lands at the offset it was sent
+        let mut tail = (1..10)
+            .step_by(2)
+            .map(|msg| (offsets[msg], msg))
+            .filter(|(offset, _)| *offset >= 3)
+            .collect::<Vec<_>>();
+        tail.sort();
+        assert_eq!(
+            client
+                .execute::<stores::LogRetrieval>(Operation::Poll {
+                    offsets: Offsets::from([("k1".to_string(), 3)]),
+                    limit: None,
+                })
+                .unwrap(),
+            stores::LogRetrieval::from([("k1".to_string(), tail)])
+        );
+    }
+
+    #[test]
+    fn takes_the_poll_limit_and_group_from_the_request() {
+        let store = shards::Shards::new(4).with_poll_limit(2);
+        for msg in 0..5 {
+            store.send("k".to_string(), msg).unwrap();
+        }
+        let response = respond(
+            &store,
+            r#"{"version":1,"id":1,"op":"poll","args":{"offsets":{"k":4,"x":0},"limit":1}}"#,
+            false,
+        );
+        assert_eq!(
+            response,
+            r#"{"version":1,"id":1,"result":{"k":[[4,4]],"x":[]}}"#
+        );
+        respond(
+            &store,
+            r#"{"version":1,"id":2,"op":"commit-offsets","args":{"offsets":{"k":1}}}"#,
+            false,
+        );
+        assert_eq!(
+            store.list_committed_offsets(stores::DEFAULT_GROUP, vec!["k".to_string()]),
+            Offsets::from([("k".to_string(), 1)])
+        );
+    }
+
+    #[test]
+    fn reports_truncated_polls_as_out_of_range() {
+        let store = shards::Shards::new(4).with_retention(stores::RetentionPolicy {
+            max_messages: Some(1),
+            ..Default::default()
+        });
+        for msg in 0..2 {
+            store.send("k".to_string(), msg).unwrap();
+        }
+        let response = respond(
+            &store,
+            r#"{"version":1,"id":1,"op":"poll","args":{"offsets":{"k":0}}}"#,
+            false,
+        );
+        assert!(response.contains(r#""kind":"out-of-range""#));
+        assert!(response.contains(r#""code":14"#));
+    }
+
+    #[test]
+    fn serves_key_value_requests_with_maelstrom_codes() {
+        let store = shards::Shards::new(4);
+        let requests = [
+            r#"{"version":1,"id":1,"op":"read","args":{"key":"x"}}"#,
+            r#"{"version":1,"id":2,"op":"cas","args":{"key":"x","from":1,"to":2}}"#,
+            r#"{"version":1,"id":3,"op":"cas","args":{"key":"x","from":1,"to":[2],"create_if_not_exists":true}}"#,
+            r#"{"version":1,"id":4,"op":"cas","args":{"key":"x","from":1,"to":3}}"#,
+            r#"{"version":1,"id":5,"op":"write","args":{"key":"x","value":{"a":1}}}"#,
+            r#"{"version":1,"id":6,"op":"read","args":{"key":"x"}}"#,
+        ];
+        let responses = requests.map(|request| respond(&store, request, false));
+        assert!(responses[0].contains(r#""code":20"#));
+        assert!(responses[1].contains(r#""code":20"#));
+        assert_eq!(responses[2], r#"{"version":1,"id":3,"result":null}"#);
+        assert!(responses[3].contains(r#""code":22"#));
+        assert_eq!(responses[5], r#"{"version":1,"id":6,"result":{"a":1}}"#);
+    }
+}
@@ -0,0 +1,128 @@
+use maelstrom_challenge::protocols::Operation;
+
+#[derive(Debug)]
+pub enum ParseRequestError {
+    InvalidFormat(String),
+    InvalidCommand(String),
+    InvalidNumber(std::num::ParseIntError),
+    EmptyString,
+}
+
+impl std::fmt::Display for ParseRequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidFormat(string) => write!(f, "invalid format: {string:?}"),
+            Self::InvalidCommand(command) => write!(f, "invalid command: {command:?}"),
+            Self::InvalidNumber(error) => write!(f, "invalid number: {error}"),
+            Self::EmptyString => write!(f, "empty request"),
+        }
+    }
+}
+
+impl From<std::num::ParseIntError> for ParseRequestError {
+    fn from(value: std::num::ParseIntError) -> Self {
+        Self::InvalidNumber(value)
+    }
+}
+
+fn take_first_token(string: &str) -> Result<(&str, &str), ParseRequestError> {
+    let Some((command, remain)) = string.split_once(':') else {
+        return Err(ParseRequestError::InvalidFormat(string.to_string()));
+    };
+    Ok((command, remain))
+}
+
+fn parse_offsets(mut remain: &str) -> Result<crate::stores::Offsets, ParseRequestError> {
+    let mut offsets = crate::stores::Offsets::new();
+    loop {
+        let (key, new_remain) = take_first_token(remain)?;
+        remain = new_remain;
+        if key.is_empty() {
+            break;
+        }
+        let (offset_string, new_remain) = take_first_token(remain)?;
+        remain = new_remain;
+        let offset = offset_string.parse()?;
+        offsets.insert(key.to_string(), offset);
+    }
+    Ok(offsets)
+}
+
+// legacy `command:key:value::` text format, only accepted with --legacy-protocol
+pub fn parse(request_string: &str) -> Result<Operation, ParseRequestError> {
+    if request_string.is_empty() {
+        return Err(ParseRequestError::EmptyString);
+    }
+    let (command, mut remain) = take_first_token(request_string)?;
+    match command {
+        "send" => {
+            let (key, msg) = take_first_token(remain)?;
+            Ok(Operation::Send {
+                key: key.to_string(),
+                msg: msg.parse()?,
+            })
+        }
+        "poll" => {
+            let offsets = parse_offsets(remain)?;
+            Ok(Operation::Poll {
+                offsets,
+                limit: None,
+            })
+        }
+        "commit-offsets" => {
+            let offsets = parse_offsets(remain)?;
+            Ok(Operation::CommitOffsets {
+                offsets,
+                group: crate::stores::DEFAULT_GROUP.to_string(),
+            })
+        }
+        "list-committed-offsets" => {
+            let mut keys = Vec::new();
+            loop {
+                let (key, new_remain) = take_first_token(remain)?;
+                remain = new_remain;
+                if key.is_empty() {
+                    break;
+                }
+                keys.push(key.to_string())
+            }
+            Ok(Operation::ListCommittedOffsets {
+                keys,
+                group: crate::stores::DEFAULT_GROUP.to_string(),
+            })
+        }
+        "counter-read" => Ok(Operation::CounterRead {
+            key: remain.to_string(),
+        }),
+        "counter-add" => {
+            let (key, delta) = take_first_token(remain)?;
+            Ok(Operation::CounterAdd {
+                key: key.to_string(),
+                delta: delta.parse()?,
+            })
+        }
+        "snapshot" => Ok(Operation::Snapshot),
+        command => Err(ParseRequestError::InvalidCommand(command.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_counter_requests() {
+        assert!(matches!(
+            parse("counter-read:g-counter"),
+            Ok(Operation::CounterRead { key }) if key == "g-counter"
+        ));
+        assert!(matches!(
+            parse("counter-add:g-counter:5"),
+            Ok(Operation::CounterAdd { key, delta: 5 }) if key == "g-counter"
+        ));
+        assert!(matches!(
+            parse("counter-add:g-counter:x"),
+            Err(ParseRequestError::InvalidNumber(_))
+        ));
+    }
+}
@@ -0,0 +1,162 @@
+use crate::stores::{RetentionPolicy, Store};
+
+// offset below which the policy drops the messages of the key at the given time
+pub fn retention_point(store: &Store, key: &str, policy: RetentionPolicy, now: u64) -> usize {
+    let Some(log) = store.logs.get(key) else {
+        return 0;
+    };
+    let by_count = policy
+        .max_messages
+        .map(|max_messages| log.end().saturating_sub(max_messages))
+        .unwrap_or_default();
+    let by_age = policy
+        .max_age_ms
+        .map(|max_age_ms| {
+            log.start
+                + log
+                    .messages
+                    .iter()
+                    .take_while(|(_, timestamp)| now.saturating_sub(*timestamp) > max_age_ms)
+                    .count()
+        })
+        .unwrap_or_default();
+    // a known group that has not committed the key yet holds back all of it
+    let by_committed = match policy.below_committed {
+        true => store
+            .offset_registry
+            .values()
+            .map(|offsets| offsets.get(key).copied().unwrap_or_default())
+            .min()
+            .unwrap_or_default(),
+        false => 0,
+    };
+    by_count
+        .max(by_age)
+        .max(by_committed)
+        .clamp(log.start, log.end())
+}
+
+pub fn truncate(store: &mut Store, key: &str, offset: usize) {
+    let Some(log) = store.logs.get_mut(key) else {
+        return;
+    };
+    let offset = offset.min(log.end());
+    if offset > log.start {
+        log.messages.drain(..offset - log.start);
+        log.start = offset;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stores::{LogRetrieval, Offsets, OutOfRange};
+
+    #[test]
+    fn picks_the_highest_retention_point_within_the_log() {
+        let mut store = Store::default();
+        for msg in 0..5 {
+            store.send("k".to_string(), msg, 100 * (msg as u64 + 1));
+        }
+        let point = |store: &Store, max_messages, max_age_ms, below_committed| {
+            let policy = RetentionPolicy {
+                max_messages,
+                max_age_ms,
+                below_committed,
+            };
+            retention_point(store, "k", policy, 500)
+        };
+        assert_eq!(point(&store, None, None, false), 0);
+        assert_eq!(point(&store, Some(2), None, false), 3);
+        // messages strictly older than the age are dropped
+        assert_eq!(point(&store, None, Some(250), false), 2);
+        assert_eq!(point(&store, Some(4), Some(250), false), 2);
+        assert_eq!(point(&store, Some(0), None, false), 5);
+        assert_eq!(point(&store, None, None, true), 0);
+        store.commit_offsets("a".to_string(), Offsets::from([("k".to_string(), 4)]));
+        assert_eq!(point(&store, None, None, true), 4);
+        store.commit_offsets("b".to_string(), Offsets::new());
+        assert_eq!(point(&store, None, None, true), 0);
+        store.commit_offsets("b".to_string(), Offsets::from([("k".to_string(), 1)]));
+        assert_eq!(point(&store, None, None, true), 1);
+        truncate(&mut store, "k", 2);
+        assert_eq!(point(&store, None, None, true), 2);
+        let policy = RetentionPolicy {
+            max_messages: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(retention_point(&store, "x", policy, 500), 0);
+    }
+
+    #[test]
+    fn truncates_logs_with_stable_offsets() {
+        let store = crate::shards::Shards::new(4).with_retention(RetentionPolicy {
+            max_messages: Some(3),
+            ..Default::default()
+        });
+        for msg in 0..5 {
+            assert_eq!(store.send("k".to_string(), msg).unwrap(), msg);
+        }
+        assert!(matches!(
+            store.poll(Offsets::from([("k".to_string(), 1)]), None),
+            Err(OutOfRange { start: 2, .. })
+        ));
+        store
+            .set_retention(
+                "k".to_string(),
+                RetentionPolicy {
+                    below_committed: true,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        store
+            .commit_offsets("b".to_string(), Offsets::from([("k".to_string(), 3)]))
+            .unwrap();
+        store
+            .commit_offsets("a".to_string(), Offsets::from([("k".to_string(), 4)]))
+            .unwrap();
+        assert_eq!(
+            store
+                .poll(Offsets::from([("k".to_string(), 3)]), None)
+                .unwrap(),
+            LogRetrieval::from([("k".to_string(), vec![(3, 3), (4, 4)])])
+        );
+        assert!(matches!(
+            store.poll(Offsets::from([("k".to_string(), 2)]), None),
+            Err(OutOfRange { start: 3, .. })
+        ));
+        assert_eq!(store.send("k".to_string(), 5).unwrap(), 5);
+    }
+
+    #[test]
+    fn truncates_below_committed_only_once_every_group_committed() {
+        let store = crate::shards::Shards::new(4).with_retention(RetentionPolicy {
+            below_committed: true,
+            ..Default::default()
+        });
+        for key in ["k", "x"] {
+            for msg in 0..4 {
+                store.send(key.to_string(), msg).unwrap();
+            }
+        }
+        store
+            .commit_offsets("b".to_string(), Offsets::from([("x".to_string(), 3)]))
+            .unwrap();
+        // b is known from another key, so it still holds back all of k
+        store
+            .commit_offsets("a".to_string(), Offsets::from([("k".to_string(), 2)]))
+            .unwrap();
+        store.enforce_retentions();
+        assert!(store
+            .poll(Offsets::from([("k".to_string(), 0)]), None)
+            .is_ok());
+        store
+            .commit_offsets("b".to_string(), Offsets::from([("k".to_string(), 1)]))
+            .unwrap();
+        assert!(matches!(
+            store.poll(Offsets::from([("k".to_string(), 0)]), None),
+            Err(OutOfRange { start: 1, .. })
+        ));
+    }
+}
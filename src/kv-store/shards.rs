@@ -0,0 +1,590 @@
+use crate::journals::{Journal, Record};
+use crate::protocols::Operation;
+use crate::snapshots::{Snapshot, Snapshots};
+use crate::stores::{LogRetrieval, Offsets, OutOfRange, RetentionPolicy, Store};
+
+// every key lives in exactly one shard and every operation on a key runs under
+// that shard's lock, which keeps operations on a single key linearizable
+pub struct Shards {
+    shards: Vec<std::sync::Mutex<Store>>,
+    journal: Option<Journal>,
+    snapshots: Option<Snapshots>,
+    poll_limit: usize,
+    retention: RetentionPolicy,
+}
+
+impl Shards {
+    pub fn new(count: usize) -> Self {
+        Self {
+            shards: (0..count.max(1))
+                .map(|_| std::sync::Mutex::new(Store::default()))
+                .collect(),
+            journal: None,
+            snapshots: None,
+            poll_limit: crate::stores::DEFAULT_POLL_LIMIT,
+            retention: RetentionPolicy::default(),
+        }
+    }
+
+    pub fn recover(
+        count: usize,
+        snapshots: Option<(Snapshots, Snapshot)>,
+        journal: Option<(Journal, Vec<Record>)>,
+    ) -> std::io::Result<Self> {
+        let mut shards = Self::new(count);
+        let (snapshots, snapshot) = match snapshots {
+            Some((snapshots, snapshot)) => (Some(snapshots), snapshot),
+            None => (None, Snapshot::default()),
+        };
+        for (key, log) in snapshot.store.logs {
+            shards
+                .lock(shards.index(key.as_str()))
+                .logs
+                .insert(key, log);
+        }
+        for (group, offsets) in snapshot.store.offset_registry {
+            shards.register_group(group.as_str());
+            for (key, offset) in offsets {
+                shards
+                    .lock(shards.index(key.as_str()))
+                    .offset_registry
+                    .entry(group.clone())
+                    .or_default()
+                    .insert(key, offset);
+            }
+        }
+        for (key, counter) in snapshot.store.counters {
+            shards
+                .lock(shards.index(key.as_str()))
+                .counters
+                .insert(key, counter);
+        }
+        for (key, value) in snapshot.store.values {
+            shards
+                .lock(shards.index(key.as_str()))
+                .values
+                .insert(key, value);
+        }
+        for (key, policy) in snapshot.store.retention {
+            shards
+                .lock(shards.index(key.as_str()))
+                .retention
+                .insert(key, policy);
+        }
+        shards.snapshots = snapshots;
+        if let Some((journal, records)) = journal {
+            let behind = records.len() < snapshot.journal_records;
+            // the snapshot already covers the head of the journal
+            records
+                .into_iter()
+                .skip(snapshot.journal_records)
+                .for_each(|record| shards.replay(record));
+            shards.journal = Some(journal);
+            // records the snapshot counted were lost before reaching the disk, so
+            // the state is snapshotted again against the journal as it is now,
+            // otherwise the next recovery would skip records appended from here on
+            if behind {
+                eprintln!(
+                    "the journal has {} records but the snapshot covers {}, rebasing the snapshot",
+                    shards
+                        .journal
+                        .as_ref()
+                        .map(Journal::records)
+                        .unwrap_or_default(),
+                    snapshot.journal_records
+                );
+                shards.snapshot()?;
+            }
+        }
+        Ok(shards)
+    }
+
+    pub fn with_poll_limit(mut self, poll_limit: usize) -> Self {
+        self.poll_limit = poll_limit;
+        self
+    }
+
+    pub fn with_retention(mut self, retention: RetentionPolicy) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    fn capture(&self) -> std::io::Result<Snapshot> {
+        // holding every shard lock at once gives a point-in-time view
+        let stores = (0..self.shards.len())
+            .map(|index| self.lock(index))
+            .collect::<Vec<_>>();
+        // the records a snapshot covers must be on disk, or a crash could leave the
+        // journal shorter than the snapshot claims
+        let journal_records = match &self.journal {
+            Some(journal) => {
+                journal.sync()?;
+                journal.records()
+            }
+            None => 0,
+        };
+        let mut snapshot = Snapshot {
+            journal_records,
+            store: Store::default(),
+        };
+        for store in stores.iter() {
+            snapshot.store.logs.extend(store.logs.clone());
+            for (group, offsets) in store.offset_registry.iter() {
+                snapshot
+                    .store
+                    .offset_registry
+                    .entry(group.clone())
+                    .or_default()
+                    .extend(offsets.clone());
+            }
+            snapshot.store.counters.extend(store.counters.clone());
+            snapshot.store.retention.extend(store.retention.clone());
+            snapshot.store.values.extend(store.values.clone());
+        }
+        Ok(snapshot)
+    }
+
+    pub fn snapshot(&self) -> std::io::Result<u64> {
+        match &self.snapshots {
+            Some(snapshots) => snapshots.write(|| self.capture()),
+            None => Err(std::io::Error::other("snapshots are not enabled")),
+        }
+    }
+
+    // replayed records only repeat what the journal saw, truncations included,
+    // so retention is not enforced again here
+    fn replay(&self, record: Record) {
+        let timestamp = record.timestamp.unwrap_or_else(crate::stores::now);
+        match record.operation {
+            Operation::Send { key, msg } => {
+                self.lock(self.index(key.as_str()))
+                    .send(key, msg, timestamp);
+            }
+            Operation::CommitOffsets { offsets, group } => {
+                self.register_group(group.as_str());
+                for (index, offsets) in self.split(offsets) {
+                    self.lock(index).commit_offsets(group.clone(), offsets)
+                }
+            }
+            Operation::CounterAdd { key, delta } => {
+                self.lock(self.index(key.as_str())).counter_add(key, delta);
+            }
+            Operation::SetRetention { key, policy } => {
+                self.lock(self.index(key.as_str()))
+                    .retention
+                    .insert(key, policy);
+            }
+            Operation::Truncate { key, offset } => {
+                let mut store = self.lock(self.index(key.as_str()));
+                crate::retentions::truncate(&mut store, key.as_str(), offset)
+            }
+            Operation::Write { key, value } => {
+                self.lock(self.index(key.as_str()))
+                    .values
+                    .insert(key, value);
+            }
+            // successful compare-and-sets are journaled as writes
+            Operation::Cas { .. }
+            | Operation::Read { .. }
+            | Operation::Poll { .. }
+            | Operation::ListCommittedOffsets { .. }
+            | Operation::ListGroups
+            | Operation::GroupLag { .. }
+            | Operation::CounterRead { .. }
+            | Operation::Snapshot => {}
+        }
+    }
+
+    // called with the shard lock held so that the journal orders the writes to
+    // a key the same way the shard applies them, an operation is only applied
+    // once its record was appended
+    fn record(&self, operation: Operation, timestamp: u64) -> std::io::Result<()> {
+        match &self.journal {
+            Some(journal) => journal.append(&Record {
+                timestamp: Some(timestamp),
+                operation,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn enforce_retention(&self, store: &mut Store, key: &str, now: u64) {
+        let policy = store.retention.get(key).copied().unwrap_or(self.retention);
+        let offset = crate::retentions::retention_point(store, key, policy, now);
+        if store.logs.get(key).is_some_and(|log| offset > log.start) {
+            let recorded = self.record(
+                Operation::Truncate {
+                    key: key.to_string(),
+                    offset,
+                },
+                now,
+            );
+            // retention is retried on the next write or sweep
+            match recorded {
+                Ok(()) => crate::retentions::truncate(store, key, offset),
+                Err(error) => eprintln!("failed to journal the truncation of {key:?}: {error}"),
+            }
+        }
+    }
+
+    // every shard knows every group, so that retention below committed offsets
+    // also waits for groups that committed no key of the shard yet
+    fn register_group(&self, group: &str) {
+        for index in 0..self.shards.len() {
+            let mut store = self.lock(index);
+            if !store.offset_registry.contains_key(group) {
+                store
+                    .offset_registry
+                    .insert(group.to_string(), Offsets::new());
+            }
+        }
+    }
+
+    fn index(&self, key: &str) -> usize {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        std::hash::Hash::hash(key, &mut hasher);
+        std::hash::Hasher::finish(&hasher) as usize % self.shards.len()
+    }
+
+    fn lock(&self, index: usize) -> std::sync::MutexGuard<'_, Store> {
+        self.shards[index].lock().expect("shard lock poisoned")
+    }
+
+    fn split<T>(
+        &self,
+        entries: impl IntoIterator<Item = (String, T)>,
+    ) -> std::collections::BTreeMap<usize, std::collections::BTreeMap<String, T>> {
+        let mut split =
+            std::collections::BTreeMap::<usize, std::collections::BTreeMap<_, _>>::new();
+        for (key, value) in entries {
+            split
+                .entry(self.index(key.as_str()))
+                .or_default()
+                .insert(key, value);
+        }
+        split
+    }
+
+    pub fn send(&self, key: String, msg: usize) -> Result<usize, crate::protocols::Error> {
+        let now = crate::stores::now();
+        let mut store = self.lock(self.index(key.as_str()));
+        self.record(
+            Operation::Send {
+                key: key.clone(),
+                msg,
+            },
+            now,
+        )
+        .map_err(journal_failed)?;
+        let offset = store.send(key.clone(), msg, now);
+        self.enforce_retention(&mut store, key.as_str(), now);
+        Ok(offset)
+    }
+
+    pub fn poll(&self, offsets: Offsets, limit: Option<usize>) -> Result<LogRetrieval, OutOfRange> {
+        let limit = limit.unwrap_or(self.poll_limit);
+        let mut retrieved = LogRetrieval::new();
+        for (index, offsets) in self.split(offsets) {
+            retrieved.extend(self.lock(index).poll(offsets, limit)?);
+        }
+        Ok(retrieved)
+    }
+
+    pub fn commit_offsets(
+        &self,
+        group: String,
+        offsets: Offsets,
+    ) -> Result<(), crate::protocols::Error> {
+        let now = crate::stores::now();
+        self.register_group(group.as_str());
+        for (index, offsets) in self.split(offsets) {
+            let mut store = self.lock(index);
+            self.record(
+                Operation::CommitOffsets {
+                    offsets: offsets.clone(),
+                    group: group.clone(),
+                },
+                now,
+            )
+            .map_err(journal_failed)?;
+            store.commit_offsets(group.clone(), offsets.clone());
+            for key in offsets.keys() {
+                self.enforce_retention(&mut store, key.as_str(), now);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_retention(
+        &self,
+        key: String,
+        policy: RetentionPolicy,
+    ) -> Result<(), crate::protocols::Error> {
+        let now = crate::stores::now();
+        let mut store = self.lock(self.index(key.as_str()));
+        self.record(
+            Operation::SetRetention {
+                key: key.clone(),
+                policy,
+            },
+            now,
+        )
+        .map_err(journal_failed)?;
+        store.retention.insert(key.clone(), policy);
+        self.enforce_retention(&mut store, key.as_str(), now);
+        Ok(())
+    }
+
+    pub fn truncate(&self, key: String, offset: usize) -> Result<(), crate::protocols::Error> {
+        let now = crate::stores::now();
+        let mut store = self.lock(self.index(key.as_str()));
+        self.record(
+            Operation::Truncate {
+                key: key.clone(),
+                offset,
+            },
+            now,
+        )
+        .map_err(journal_failed)?;
+        crate::retentions::truncate(&mut store, key.as_str(), offset);
+        Ok(())
+    }
+
+    // applies the retention policies that depend on time passing rather than on writes
+    pub fn enforce_retentions(&self) {
+        let now = crate::stores::now();
+        for index in 0..self.shards.len() {
+            let mut store = self.lock(index);
+            let keys = store.logs.keys().cloned().collect::<Vec<_>>();
+            for key in keys {
+                self.enforce_retention(&mut store, key.as_str(), now);
+            }
+        }
+    }
+
+    pub fn list_committed_offsets(&self, group: &str, keys: Vec<String>) -> Offsets {
+        self.split(keys.into_iter().map(|key| (key, ())))
+            .into_iter()
+            .flat_map(|(index, keys)| {
+                self.lock(index)
+                    .list_committed_offsets(group, keys.into_keys().collect())
+            })
+            .collect()
+    }
+
+    pub fn groups(&self) -> Vec<String> {
+        (0..self.shards.len())
+            .flat_map(|index| self.lock(index).groups())
+            .collect::<std::collections::BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    }
+
+    pub fn lag(&self, group: &str) -> Offsets {
+        (0..self.shards.len())
+            .flat_map(|index| self.lock(index).lag(group))
+            .collect()
+    }
+
+    pub fn counter_read(&self, key: &str) -> usize {
+        self.lock(self.index(key)).counter_read(key)
+    }
+
+    pub fn counter_add(&self, key: String, delta: usize) -> Result<usize, crate::protocols::Error> {
+        let now = crate::stores::now();
+        let mut store = self.lock(self.index(key.as_str()));
+        self.record(
+            Operation::CounterAdd {
+                key: key.clone(),
+                delta,
+            },
+            now,
+        )
+        .map_err(journal_failed)?;
+        Ok(store.counter_add(key, delta))
+    }
+
+    pub fn read(&self, key: &str) -> Result<serde_json::Value, crate::protocols::Error> {
+        self.lock(self.index(key))
+            .values
+            .get(key)
+            .cloned()
+            .ok_or_else(|| {
+                crate::protocols::Error::new(
+                    crate::protocols::ErrorKind::KeyDoesNotExist,
+                    format!("key {key:?} does not exist"),
+                )
+            })
+    }
+
+    pub fn write(
+        &self,
+        key: String,
+        value: serde_json::Value,
+    ) -> Result<(), crate::protocols::Error> {
+        let now = crate::stores::now();
+        let mut store = self.lock(self.index(key.as_str()));
+        self.record(
+            Operation::Write {
+                key: key.clone(),
+                value: value.clone(),
+            },
+            now,
+        )
+        .map_err(journal_failed)?;
+        store.values.insert(key, value);
+        Ok(())
+    }
+
+    pub fn cas(
+        &self,
+        key: String,
+        from: serde_json::Value,
+        to: serde_json::Value,
+        create_if_not_exists: bool,
+    ) -> Result<(), crate::protocols::Error> {
+        let now = crate::stores::now();
+        let mut store = self.lock(self.index(key.as_str()));
+        match store.values.get(&key) {
+            None if !create_if_not_exists => {
+                return Err(crate::protocols::Error::new(
+                    crate::protocols::ErrorKind::KeyDoesNotExist,
+                    format!("key {key:?} does not exist"),
+                ))
+            }
+            Some(current) if *current != from => {
+                return Err(crate::protocols::Error::new(
+                    crate::protocols::ErrorKind::PreconditionFailed,
+                    format!("expect {from}, got {current}"),
+                ))
+            }
+            None | Some(_) => {}
+        }
+        self.record(
+            Operation::Write {
+                key: key.clone(),
+                value: to.clone(),
+            },
+            now,
+        )
+        .map_err(journal_failed)?;
+        store.values.insert(key, to);
+        Ok(())
+    }
+}
+
+fn journal_failed(error: std::io::Error) -> crate::protocols::Error {
+    crate::protocols::Error::new(
+        crate::protocols::ErrorKind::JournalFailed,
+        format!("failed to append to the journal: {error}"),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn keeps_per_key_operations_atomic_across_workers() {
+        let store = std::sync::Arc::new(Shards::new(4));
+        let workers = crate::workers::Workers::new(8);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        for _ in 0..100 {
+            let store = store.clone();
+            let sender = sender.clone();
+            workers.submit(move || {
+                sender
+                    .send(store.send("k".to_string(), 1).unwrap())
+                    .unwrap();
+                store.counter_add("c".to_string(), 1).unwrap();
+            });
+        }
+        drop(sender);
+        let mut offsets = receiver.iter().collect::<Vec<_>>();
+        offsets.sort();
+        assert_eq!(offsets, (0..100).collect::<Vec<_>>());
+        assert_eq!(store.counter_read("c"), 100);
+    }
+
+    #[test]
+    fn keeps_appending_after_a_journal_shorter_than_the_snapshot() {
+        let directory =
+            std::env::temp_dir().join(format!("kv-store-{}-behind", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let journal_path = directory.join("journal");
+        let open = || {
+            let (snapshots, snapshot) = crate::snapshots::Snapshots::open(&directory).unwrap();
+            let journal =
+                crate::journals::Journal::open(&journal_path, crate::journals::SyncPolicy::Never)
+                    .unwrap();
+            Shards::recover(
+                4,
+                Some((snapshots, snapshot.unwrap_or_default())),
+                Some(journal),
+            )
+            .unwrap()
+        };
+        {
+            let store = open();
+            store.send("k".to_string(), 1).unwrap();
+            store.send("k".to_string(), 2).unwrap();
+            store.snapshot().unwrap();
+        }
+        // the second record never reached the disk although the snapshot counted it
+        let content = std::fs::read(&journal_path).unwrap();
+        let first_record = content.iter().position(|byte| *byte == b'\n').unwrap() + 1;
+        std::fs::write(&journal_path, &content[..first_record]).unwrap();
+        {
+            let store = open();
+            assert_eq!(store.send("k".to_string(), 3).unwrap(), 2);
+        }
+        let store = open();
+        assert_eq!(
+            store
+                .poll(Offsets::from([("k".to_string(), 0)]), None)
+                .unwrap(),
+            LogRetrieval::from([("k".to_string(), vec![(0, 1), (1, 2), (2, 3)])])
+        );
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn polls_up_to_the_limit_regardless_of_commits() {
+        let store = Shards::new(4).with_poll_limit(2);
+        for msg in 0..5 {
+            store.send("k".to_string(), msg).unwrap();
+        }
+        let offsets = Offsets::from([("k".to_string(), 1)]);
+        assert_eq!(
+            store.poll(offsets.clone(), None).unwrap(),
+            LogRetrieval::from([("k".to_string(), vec![(1, 1), (2, 2)])])
+        );
+        assert_eq!(
+            store.poll(offsets, Some(10)).unwrap(),
+            LogRetrieval::from([("k".to_string(), vec![(1, 1), (2, 2), (3, 3), (4, 4)])])
+        );
+    }
+
+    #[test]
+    fn scopes_committed_offsets_by_group() {
+        let store = Shards::new(4);
+        for msg in 0..4 {
+            store.send("k".to_string(), msg).unwrap();
+        }
+        store
+            .commit_offsets(
+                crate::stores::DEFAULT_GROUP.to_string(),
+                Offsets::from([("k".to_string(), 0)]),
+            )
+            .unwrap();
+        store
+            .commit_offsets("g".to_string(), Offsets::from([("k".to_string(), 2)]))
+            .unwrap();
+        assert_eq!(
+            store.list_committed_offsets(crate::stores::DEFAULT_GROUP, vec!["k".to_string()]),
+            Offsets::from([("k".to_string(), 0)])
+        );
+        assert_eq!(store.groups(), ["default", "g"]);
+        assert_eq!(store.lag("g"), Offsets::from([("k".to_string(), 1)]));
+    }
+}
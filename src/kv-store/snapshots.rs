@@ -0,0 +1,174 @@
+const RETAINED_SNAPSHOTS: usize = 3;
+
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct Snapshot {
+    pub journal_records: usize,
+    pub store: crate::stores::Store,
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+// a snapshot file is the hex checksum of the body on the first line followed by the json body
+fn encode(snapshot: &Snapshot) -> Vec<u8> {
+    let body = serde_json::to_vec(snapshot).expect("failed to serialize");
+    let mut content = format!("{:016x}\n", fnv1a(&body)).into_bytes();
+    content.extend(body);
+    content
+}
+
+fn decode(content: &[u8]) -> Option<Snapshot> {
+    let (checksum, body) = content.split_at(content.iter().position(|byte| *byte == b'\n')?);
+    let body = &body[1..];
+    if u64::from_str_radix(std::str::from_utf8(checksum).ok()?, 16).ok()? != fnv1a(body) {
+        return None;
+    }
+    serde_json::from_slice(body).ok()
+}
+
+#[derive(Debug)]
+pub struct Snapshots {
+    directory: std::path::PathBuf,
+    generation: std::sync::Mutex<u64>,
+}
+
+impl Snapshots {
+    fn path(&self, generation: u64) -> std::path::PathBuf {
+        self.directory.join(format!("snapshot-{generation:020}"))
+    }
+
+    fn generations(directory: &std::path::Path) -> std::io::Result<Vec<u64>> {
+        let mut generations = std::fs::read_dir(directory)?
+            .filter_map(|entry| {
+                entry
+                    .ok()?
+                    .file_name()
+                    .to_str()?
+                    .strip_prefix("snapshot-")?
+                    .parse()
+                    .ok()
+            })
+            .collect::<Vec<u64>>();
+        generations.sort();
+        Ok(generations)
+    }
+
+    pub fn open(directory: &std::path::Path) -> std::io::Result<(Self, Option<Snapshot>)> {
+        std::fs::create_dir_all(directory)?;
+        let generations = Self::generations(directory)?;
+        let snapshots = Self {
+            directory: directory.to_path_buf(),
+            generation: std::sync::Mutex::new(generations.last().copied().unwrap_or_default()),
+        };
+        let snapshot = generations.iter().rev().find_map(|generation| {
+            let path = snapshots.path(*generation);
+            let snapshot = std::fs::read(&path)
+                .ok()
+                .and_then(|content| decode(&content));
+            if snapshot.is_none() {
+                eprintln!("skipping corrupt snapshot {}", path.display());
+            }
+            snapshot
+        });
+        Ok((snapshots, snapshot))
+    }
+
+    pub fn write(
+        &self,
+        capture: impl FnOnce() -> std::io::Result<Snapshot>,
+    ) -> std::io::Result<u64> {
+        // capturing under the lock keeps generations in the same order as their contents
+        let mut generation = self.generation.lock().expect("snapshot lock poisoned");
+        let snapshot = capture()?;
+        let next_generation = *generation + 1;
+        let path = self.path(next_generation);
+        // the snapshot only becomes visible under its final name once fully on disk
+        let temporary_path = path.with_extension("tmp");
+        let mut file = std::fs::File::create(&temporary_path)?;
+        std::io::Write::write_all(&mut file, &encode(&snapshot))?;
+        file.sync_all()?;
+        std::fs::rename(&temporary_path, &path)?;
+        std::fs::File::open(&self.directory)?.sync_all()?;
+        *generation = next_generation;
+
+        let generations = Self::generations(&self.directory)?;
+        for generation in generations
+            .iter()
+            .take(generations.len().saturating_sub(RETAINED_SNAPSHOTS))
+        {
+            std::fs::remove_file(self.path(*generation))?;
+        }
+        Ok(next_generation)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decodes_only_snapshots_with_a_matching_checksum() {
+        let mut snapshot = Snapshot {
+            journal_records: 3,
+            ..Default::default()
+        };
+        snapshot.store.counter_add("c".to_string(), 2);
+        let content = encode(&snapshot);
+        let decoded = decode(&content).unwrap();
+        assert_eq!(decoded.journal_records, 3);
+        assert_eq!(decoded.store.counter_read("c"), 2);
+        let mut corrupt = content.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        assert!(decode(&corrupt).is_none());
+        let newline = content.iter().position(|byte| *byte == b'\n').unwrap();
+        assert!(decode(&content[newline + 1..]).is_none());
+        assert!(decode(&content[..newline]).is_none());
+        assert!(decode(b"not hex\n{}").is_none());
+    }
+
+    #[test]
+    fn recovers_from_the_newest_valid_snapshot() {
+        let directory =
+            std::env::temp_dir().join(format!("kv-store-{}-snapshots", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let journal_path = directory.join("journal");
+        let open = || {
+            let (snapshots, snapshot) = Snapshots::open(&directory).unwrap();
+            let journal =
+                crate::journals::Journal::open(&journal_path, crate::journals::SyncPolicy::Never)
+                    .unwrap();
+            crate::shards::Shards::recover(
+                4,
+                Some((snapshots, snapshot.unwrap_or_default())),
+                Some(journal),
+            )
+            .unwrap()
+        };
+        {
+            let store = open();
+            store.send("k".to_string(), 1).unwrap();
+            assert_eq!(store.snapshot().unwrap(), 1);
+            store.send("k".to_string(), 2).unwrap();
+            assert_eq!(store.snapshot().unwrap(), 2);
+            store.send("k".to_string(), 3).unwrap();
+        }
+        let newest = directory.join(format!("snapshot-{:020}", 2));
+        let mut content = std::fs::read(&newest).unwrap();
+        *content.last_mut().unwrap() ^= 1;
+        std::fs::write(&newest, content).unwrap();
+
+        let store = open();
+        assert_eq!(
+            store
+                .poll(crate::stores::Offsets::from([("k".to_string(), 0)]), None)
+                .unwrap(),
+            crate::stores::LogRetrieval::from([("k".to_string(), vec![(0, 1), (1, 2), (2, 3)])])
+        );
+        assert_eq!(store.send("k".to_string(), 4).unwrap(), 3);
+        assert_eq!(store.snapshot().unwrap(), 3);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,48 @@
+type Job = Box<dyn FnOnce() + Send>;
+
+pub struct Workers {
+    sender: std::sync::mpsc::Sender<Job>,
+}
+
+impl Workers {
+    pub fn new(count: usize) -> Self {
+        let (sender, receiver) = std::sync::mpsc::channel::<Job>();
+        let receiver = std::sync::Arc::new(std::sync::Mutex::new(receiver));
+        for _ in 0..count.max(1) {
+            let receiver = receiver.clone();
+            std::thread::spawn(move || loop {
+                let job = receiver.lock().expect("job queue lock poisoned").recv();
+                let Ok(job) = job else {
+                    break;
+                };
+                // a panicking job only loses its own request, the worker keeps serving
+                if std::panic::catch_unwind(std::panic::AssertUnwindSafe(job)).is_err() {
+                    eprintln!("a job panicked, dropping its request");
+                }
+            });
+        }
+        Self { sender }
+    }
+
+    pub fn submit(&self, job: impl FnOnce() + Send + 'static) {
+        self.sender
+            .send(Box::new(job))
+            .expect("worker threads exited");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn keeps_workers_alive_after_a_job_panics() {
+        let workers = Workers::new(1);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        workers.submit(|| panic!("job failed"));
+        workers.submit(move || sender.send(()).unwrap());
+        assert!(receiver
+            .recv_timeout(std::time::Duration::from_secs(5))
+            .is_ok());
+    }
+}
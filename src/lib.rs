@@ -0,0 +1,219 @@
+//! Building blocks for maelstrom nodes: the message envelope, a single-threaded
+//! node runtime with a handler registry, rpc with timeouts and timers, and
+//! clients for the maelstrom kv services and the kv-store.
+//!
+//! A challenge keeps its own state in the [`contexts::Context`], registers its
+//! handlers on a [`nodes::Node`] and runs it, see `src/bin` for each challenge:
+//!
+//! ```no_run
+//! #[derive(serde::Deserialize)]
+//! struct EchoRequest {
+//!     echo: String,
+//! }
+//!
+//! #[derive(serde::Serialize)]
+//! struct EchoResponse {
+//!     echo: String,
+//! }
+//!
+//! let mut node = maelstrom_challenge::nodes::Node::new(
+//!     maelstrom_challenge::contexts::Context::new(()),
+//! );
+//! node.handle(
+//!     "echo",
+//!     |_: &mut maelstrom_challenge::contexts::Context,
+//!      request: maelstrom_challenge::nodes::Request<EchoRequest>| {
+//!         Ok(EchoResponse {
+//!             echo: request.body.echo,
+//!         })
+//!     },
+//! );
+//! node.run();
+//! ```
+
+#![warn(missing_docs)]
+
+pub mod contexts;
+pub mod endpoints;
+pub mod errors;
+pub mod nodes;
+pub mod protocols;
+pub mod rpc;
+pub mod storages;
+pub mod stores;
+
+/// The body of a message read from stdin, before its type is decoded.
+#[derive(serde::Deserialize)]
+pub struct InputBody {
+    /// The message type, e.g. `echo` or `read_ok`.
+    pub r#type: String,
+    /// The id to reply to, requests that expect a reply carry one.
+    pub msg_id: Option<usize>,
+    /// The id of the request this message replies to.
+    pub in_reply_to: Option<usize>,
+    /// Every field besides the type and the message ids.
+    #[serde(flatten)]
+    pub other: std::collections::BTreeMap<String, serde_json::Value>,
+}
+
+/// A message read from stdin.
+#[derive(serde::Deserialize)]
+pub struct Input {
+    /// The sender.
+    pub src: String,
+    /// This node.
+    pub dest: String,
+    /// The body.
+    pub body: InputBody,
+}
+
+/// Bodies of the replies of the maelstrom kv services, unknown fields are ignored.
+/// Challenges that send requests of their own decode the replies with their own
+/// types, see [`contexts::Context::call`].
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "type")]
+pub enum TypedInputBody {
+    /// A kv service read a key.
+    #[serde(rename = "read_ok")]
+    KvRead {
+        /// The value of the key.
+        value: serde_json::Value,
+    },
+    /// A kv service wrote a key.
+    #[serde(rename = "write_ok")]
+    KvWrite,
+    /// A kv service compared and set a key.
+    #[serde(rename = "cas_ok")]
+    KvCas,
+    /// A request of this node failed.
+    #[serde(rename = "error")]
+    Error {
+        /// The maelstrom error code.
+        code: u64,
+        /// A message for humans, if there is one.
+        #[serde(default)]
+        text: String,
+    },
+}
+
+impl InputBody {
+    /// Decodes the body of a request once its handler is known, or of a reply
+    /// once its request is known.
+    pub fn typed_body<T>(self) -> Result<T, serde_json::Error>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        let mut body: serde_json::Map<_, _> = self.other.into_iter().collect();
+        body.insert("type".to_string(), self.r#type.into());
+        serde_json::from_value(serde_json::Value::Object(body))
+    }
+}
+
+/// Bodies of the requests to the maelstrom kv services and of error replies.
+#[derive(serde::Serialize)]
+#[serde(tag = "type")]
+pub enum TypedOutputBody {
+    /// Reads a key of a kv service.
+    #[serde(rename = "read")]
+    KvRead {
+        /// The key to read.
+        key: String,
+    },
+    /// Writes a key of a kv service.
+    #[serde(rename = "write")]
+    KvWrite {
+        /// The key to write.
+        key: String,
+        /// The new value.
+        value: serde_json::Value,
+    },
+    /// Compares and sets a key of a kv service.
+    #[serde(rename = "cas")]
+    KvCas {
+        /// The key to compare and set.
+        key: String,
+        /// The value the key must have.
+        from: serde_json::Value,
+        /// The new value.
+        to: serde_json::Value,
+        /// Whether a key that does not exist is set too.
+        create_if_not_exists: bool,
+    },
+    /// The reply to a request that failed.
+    #[serde(rename = "error")]
+    Error {
+        /// The maelstrom error code.
+        code: crate::errors::ErrorCode,
+        /// A message for humans.
+        text: String,
+    },
+}
+
+impl From<crate::errors::Error> for TypedOutputBody {
+    fn from(value: crate::errors::Error) -> Self {
+        Self::Error {
+            code: value.code,
+            text: value.text,
+        }
+    }
+}
+
+/// The body of a message written to stdout.
+#[derive(serde::Serialize)]
+pub struct OutputBody<T = TypedOutputBody> {
+    /// The id of this message.
+    pub msg_id: usize,
+    /// The id of the request this message replies to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub in_reply_to: Option<usize>,
+    /// The type and the fields of the body.
+    #[serde(flatten)]
+    pub typed_body: T,
+}
+
+/// A message written to stdout.
+#[derive(serde::Serialize)]
+pub struct Output<T = TypedOutputBody> {
+    /// This node.
+    pub src: String,
+    /// The recipient.
+    pub dest: String,
+    /// The body.
+    pub body: OutputBody<T>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn body<T>(input_string: &str) -> Result<T, serde_json::Error>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        serde_json::from_str::<InputBody>(input_string)
+            .unwrap()
+            .typed_body()
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    struct SendRequest {
+        key: String,
+        msg: usize,
+    }
+
+    #[test]
+    fn decodes_bodies_into_the_type_asked_for() {
+        let request: SendRequest =
+            body(r#"{"type":"send","msg_id":1,"key":"k","msg":2,"extra":true}"#).unwrap();
+        assert_eq!((request.key.as_str(), request.msg), ("k", 2));
+        let error =
+            body::<SendRequest>(r#"{"type":"send","msg_id":3,"key":"k","msg":"x"}"#).unwrap_err();
+        assert!(error.to_string().contains("invalid type: string \"x\""));
+        assert!(matches!(
+            body(r#"{"type":"read_ok","in_reply_to":2,"value":3}"#),
+            Ok(TypedInputBody::KvRead { value }) if value == 3
+        ));
+        // requests are not replies
+        assert!(body::<TypedInputBody>(r#"{"type":"send","msg_id":3}"#).is_err());
+    }
+}
@@ -0,0 +1,374 @@
+//! The node runtime: a registry of request handlers driven by the event loop.
+
+/// A request decoded into the body type its handler expects.
+pub struct Request<T> {
+    /// The node or client that sent the request.
+    pub src: String,
+    /// The decoded body.
+    pub body: T,
+}
+
+// where the reply to a request goes
+struct Address {
+    client: String,
+    node: String,
+    in_reply_to: usize,
+    r#type: String,
+}
+
+impl Address {
+    fn reply<S, T>(self, context: &mut crate::contexts::Context<S>, typed_body: T)
+    where
+        T: serde::Serialize,
+    {
+        let output = crate::Output {
+            src: self.node,
+            dest: self.client,
+            body: crate::OutputBody {
+                msg_id: context.read_counter_and_increment(),
+                in_reply_to: Some(self.in_reply_to),
+                typed_body,
+            },
+        };
+        context.write(&output);
+    }
+}
+
+/// Replies to one request with `<type>_ok` and an `R`, or with an error body.
+/// A handler registered with [`Node::handle_deferred`] gets one and may reply
+/// later, e.g. from the callback of a request of its own.
+#[must_use = "the request gets no reply unless the responder responds"]
+pub struct Responder<R> {
+    address: Address,
+    response: std::marker::PhantomData<R>,
+}
+
+impl<R> Responder<R>
+where
+    R: serde::Serialize,
+{
+    /// Replies with `response`.
+    pub fn respond<S, E>(self, context: &mut crate::contexts::Context<S>, response: Result<R, E>)
+    where
+        E: Into<crate::errors::Error>,
+    {
+        let response = match response {
+            Ok(response) => response,
+            Err(error) => {
+                return self
+                    .address
+                    .reply(context, crate::TypedOutputBody::from(error.into()))
+            }
+        };
+        let serde_json::Value::Object(mut body) =
+            serde_json::to_value(response).expect("failed to serialize response")
+        else {
+            panic!(
+                "{} response should serialize to an object",
+                self.address.r#type
+            );
+        };
+        body.insert(
+            "type".to_string(),
+            format!("{}_ok", self.address.r#type).into(),
+        );
+        self.address.reply(context, body)
+    }
+}
+
+type Handler<S> = Box<dyn Fn(&mut crate::contexts::Context<S>, String, crate::InputBody, Address)>;
+
+/// Dispatches incoming requests to the handler registered for their type and
+/// replies with `<type>_ok`, or with an error body when the handler fails.
+/// Each handler decodes the body into the request type it takes, a body that
+/// does not fit is answered with malformed-request.
+pub struct Node<S = ()> {
+    context: crate::contexts::Context<S>,
+    handlers: std::collections::BTreeMap<String, Handler<S>>,
+}
+
+#[derive(serde::Deserialize)]
+struct InitRequest {
+    node_id: String,
+    node_ids: Vec<String>,
+}
+
+#[derive(serde::Serialize)]
+struct InitResponse {}
+
+fn init<S>(
+    context: &mut crate::contexts::Context<S>,
+    request: Request<InitRequest>,
+) -> Result<InitResponse, crate::errors::Error> {
+    let InitRequest { node_id, node_ids } = request.body;
+    if !node_ids.contains(&node_id) {
+        return Err(crate::errors::Error::malformed_request(format!(
+            "node_ids does not contain node_id {node_id}"
+        )));
+    }
+    context.initialize(node_id, node_ids)?;
+    Ok(InitResponse {})
+}
+
+impl<S> Node<S>
+where
+    S: 'static,
+{
+    /// Creates a node that already answers `init`.
+    pub fn new(context: crate::contexts::Context<S>) -> Self {
+        let mut node = Self {
+            context,
+            handlers: std::collections::BTreeMap::new(),
+        };
+        node.handle("init", init);
+        node
+    }
+
+    /// Registers `handler` for requests of type `type`, which must not have a
+    /// handler yet. The node replies with what `handler` returns.
+    pub fn handle<T, R>(
+        &mut self,
+        r#type: &str,
+        handler: impl Fn(&mut crate::contexts::Context<S>, Request<T>) -> Result<R, crate::errors::Error>
+            + 'static,
+    ) -> &mut Self
+    where
+        T: serde::de::DeserializeOwned,
+        R: serde::Serialize,
+    {
+        self.handle_deferred(r#type, move |context, request, responder| {
+            let response = handler(context, request);
+            responder.respond(context, response)
+        })
+    }
+
+    /// Registers `handler` for requests of type `type`, which must not have a
+    /// handler yet. The node only replies once `handler` uses its responder,
+    /// so that it can wait for requests of its own without blocking the node.
+    pub fn handle_deferred<T, R>(
+        &mut self,
+        r#type: &str,
+        handler: impl Fn(&mut crate::contexts::Context<S>, Request<T>, Responder<R>) + 'static,
+    ) -> &mut Self
+    where
+        T: serde::de::DeserializeOwned,
+        R: serde::Serialize,
+    {
+        let handler: Handler<S> = Box::new(move |context, src, body, address| {
+            let responder = Responder {
+                address,
+                response: std::marker::PhantomData,
+            };
+            match body.typed_body() {
+                Ok(body) => handler(context, Request { src, body }, responder),
+                Err(error) => {
+                    let error = crate::errors::Error::malformed_request(format!(
+                        "invalid {} request: {error}",
+                        responder.address.r#type
+                    ));
+                    responder.respond(context, Err::<R, _>(error))
+                }
+            }
+        });
+        let previous = self.handlers.insert(r#type.to_string(), handler);
+        assert!(previous.is_none(), "{type} is already handled");
+        self
+    }
+
+    fn process(&mut self, input: crate::Input) {
+        let Some(msg_id) = input.body.msg_id else {
+            eprintln!(
+                "dropping request of type {} without a msg_id",
+                input.body.r#type
+            );
+            return;
+        };
+        let address = Address {
+            client: input.src.clone(),
+            node: input.dest,
+            in_reply_to: msg_id,
+            r#type: input.body.r#type.clone(),
+        };
+        let Some(handler) = self.handlers.get(&address.r#type) else {
+            let error = crate::errors::Error::not_supported(format!(
+                "request of type {} is not supported",
+                address.r#type
+            ));
+            return address.reply(&mut self.context, crate::TypedOutputBody::from(error));
+        };
+        handler(&mut self.context, input.src, input.body, address)
+    }
+
+    fn reject_malformed(&mut self, input_string: &str, error: serde_json::Error) {
+        let envelope: Option<serde_json::Value> = serde_json::from_str(input_string).ok();
+        let field = |name: &str| envelope.as_ref()?.get(name)?.as_str().map(str::to_string);
+        let msg_id = envelope
+            .as_ref()
+            .and_then(|envelope| envelope.get("body")?.get("msg_id")?.as_u64());
+        let (Some(client), Some(node), Some(msg_id)) = (field("src"), field("dest"), msg_id) else {
+            eprintln!(
+                "dropping message without a reply address: {}",
+                input_string.trim()
+            );
+            return;
+        };
+        let error = crate::errors::Error::malformed_request(format!(
+            "failed to deserialize input: {error}"
+        ));
+        let address = Address {
+            client,
+            node,
+            in_reply_to: msg_id as usize,
+            r#type: String::new(),
+        };
+        address.reply(&mut self.context, crate::TypedOutputBody::from(error));
+    }
+
+    /// Serves requests, replies, and timers until stdin closes.
+    pub fn run(mut self) {
+        loop {
+            match self.context.next_event() {
+                crate::rpc::Event::Closed => break,
+                crate::rpc::Event::Callback(callback, reply) => callback(&mut self.context, reply),
+                crate::rpc::Event::Timer(timer) => timer(&mut self.context),
+                crate::rpc::Event::Request(input) => self.process(input),
+                crate::rpc::Event::Malformed(input_string, error) => {
+                    self.reject_malformed(input_string.as_str(), error)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(serde::Deserialize)]
+    struct EchoRequest {
+        echo: String,
+    }
+
+    #[derive(serde::Serialize)]
+    struct EchoResponse {
+        echo: String,
+    }
+
+    fn echo(
+        _: &mut crate::contexts::Context,
+        request: Request<EchoRequest>,
+    ) -> Result<EchoResponse, crate::errors::Error> {
+        Ok(EchoResponse {
+            echo: request.body.echo,
+        })
+    }
+
+    #[derive(serde::Deserialize)]
+    struct LookupRequest {
+        key: String,
+    }
+
+    #[derive(serde::Serialize)]
+    struct LookupResponse {
+        value: serde_json::Value,
+    }
+
+    // replies with what lin-kv has for the key, once lin-kv replied
+    fn lookup(
+        context: &mut crate::contexts::Context,
+        request: Request<LookupRequest>,
+        responder: Responder<LookupResponse>,
+    ) {
+        context
+            .call(
+                "lin-kv".to_string(),
+                crate::TypedOutputBody::KvRead {
+                    key: request.body.key,
+                },
+                std::time::Duration::from_secs(10),
+                |context, reply| {
+                    let response = match reply {
+                        Ok(crate::TypedInputBody::KvRead { value }) => Ok(LookupResponse { value }),
+                        _ => Err(crate::errors::Error::new(
+                            crate::errors::ErrorCode::Crash,
+                            "lin-kv failed",
+                        )),
+                    };
+                    responder.respond(context, response)
+                },
+            )
+            .unwrap();
+    }
+
+    // serves `lines` until they run out and returns the bodies of the replies
+    fn serve(lines: &[&str]) -> Vec<serde_json::Value> {
+        let (context, sender, outgoing) = crate::contexts::test::context(());
+        let mut node = Node::new(context);
+        node.handle("echo", echo).handle_deferred("lookup", lookup);
+        for line in lines {
+            sender.send(line.as_bytes().to_vec()).unwrap();
+        }
+        drop(sender);
+        node.run();
+        outgoing
+            .try_iter()
+            .map(|output_string| {
+                serde_json::from_str::<serde_json::Value>(&output_string).unwrap()["body"].take()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rejects_unknown_types_and_malformed_bodies() {
+        let bodies = serve(&[
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":1,"echo":"hi"}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"txn","msg_id":2,"txn":[]}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":3,"echo":3}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":4"#,
+        ]);
+        assert_eq!(bodies[0]["type"], "echo_ok");
+        assert_eq!(bodies[0]["echo"], "hi");
+        assert_eq!(bodies[1]["in_reply_to"], 2);
+        assert_eq!(bodies[1]["code"], 10);
+        assert_eq!(bodies[2]["in_reply_to"], 3);
+        assert_eq!(bodies[2]["code"], 12);
+        // a line that is not json has no address to reply to
+        assert_eq!(bodies.len(), 3);
+    }
+
+    #[test]
+    fn initializes_the_node_once() {
+        let init = r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":0,"node_id":"n1","node_ids":["n1"]}}"#;
+        let bodies = serve(&[
+            r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":0,"node_id":"n1","node_ids":["n2"]}}"#,
+            init,
+            init,
+        ]);
+        assert_eq!(bodies[0]["code"], 12);
+        assert_eq!(bodies[1]["type"], "init_ok");
+        assert_eq!(bodies[2]["code"], 22);
+    }
+
+    #[test]
+    fn serves_requests_while_a_reply_is_deferred() {
+        let bodies = serve(&[
+            r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":0,"node_id":"n1","node_ids":["n1"]}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"lookup","msg_id":1,"key":"k"}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":2,"echo":"hi"}}"#,
+            r#"{"src":"lin-kv","dest":"n1","body":{"type":"read_ok","in_reply_to":1,"value":4}}"#,
+        ]);
+        assert_eq!(bodies[1]["type"], "read");
+        assert_eq!(bodies[1]["msg_id"], 1);
+        assert_eq!(bodies[2]["type"], "echo_ok");
+        assert_eq!(bodies[3]["type"], "lookup_ok");
+        assert_eq!(bodies[3]["in_reply_to"], 1);
+        assert_eq!(bodies[3]["value"], 4);
+    }
+
+    #[test]
+    #[should_panic(expected = "echo is already handled")]
+    fn takes_one_handler_per_type() {
+        let (context, _sender, _outgoing) = crate::contexts::test::context(());
+        Node::new(context).handle("echo", echo).handle("echo", echo);
+    }
+}
@@ -0,0 +1,202 @@
+//! The wire protocol between kv-store clients and the server.
+
+/// The protocol version requests must carry.
+pub const VERSION: u32 = 1;
+
+/// An operation on the kv-store, encoded as `op` and `args`.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "op", content = "args", rename_all = "kebab-case")]
+pub enum Operation {
+    /// Appends `msg` to the log of `key`, the result is its offset.
+    Send {
+        /// The log to append to.
+        key: String,
+        /// The message to append.
+        msg: usize,
+    },
+    /// Reads each log from its offset, the result is a [`crate::stores::LogRetrieval`].
+    Poll {
+        /// The offset to start at in each log.
+        offsets: crate::stores::Offsets,
+        /// The most messages to return per log, the server's limit otherwise.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        limit: Option<usize>,
+    },
+    /// Commits the offsets `group` processed.
+    CommitOffsets {
+        /// The offset processed in each log.
+        offsets: crate::stores::Offsets,
+        /// The consumer group, the default group if there is none.
+        #[serde(default = "default_group")]
+        group: String,
+    },
+    /// The offsets `group` committed for `keys`.
+    ListCommittedOffsets {
+        /// The logs to list the offsets of.
+        keys: Vec<String>,
+        /// The consumer group, the default group if there is none.
+        #[serde(default = "default_group")]
+        group: String,
+    },
+    /// Every consumer group that committed an offset.
+    ListGroups,
+    /// How far `group` lags behind in each log it committed to.
+    GroupLag {
+        /// The consumer group.
+        group: String,
+    },
+    /// The value of a counter.
+    CounterRead {
+        /// The counter.
+        key: String,
+    },
+    /// Adds `delta` to a counter, the result is its new value.
+    CounterAdd {
+        /// The counter.
+        key: String,
+        /// Added to the counter.
+        delta: usize,
+    },
+    /// Sets the retention policy of one log.
+    SetRetention {
+        /// The log.
+        key: String,
+        /// Replaces the server's default policy for the log.
+        policy: crate::stores::RetentionPolicy,
+    },
+    /// Drops the messages of a log below `offset`.
+    Truncate {
+        /// The log.
+        key: String,
+        /// The first offset to keep.
+        offset: usize,
+    },
+    /// Writes a snapshot, the result is its generation.
+    Snapshot,
+    /// The value of `key`.
+    Read {
+        /// The key to read.
+        key: String,
+    },
+    /// Sets `key` to `value`.
+    Write {
+        /// The key to write.
+        key: String,
+        /// The new value.
+        value: serde_json::Value,
+    },
+    /// Sets `key` to `to` if it is `from`.
+    Cas {
+        /// The key to compare and set.
+        key: String,
+        /// The value `key` must have.
+        from: serde_json::Value,
+        /// The new value.
+        to: serde_json::Value,
+        /// Whether a key that does not exist is set too.
+        #[serde(default)]
+        create_if_not_exists: bool,
+    },
+}
+
+/// The group of requests that name none.
+pub fn default_group() -> String {
+    crate::stores::DEFAULT_GROUP.to_string()
+}
+
+/// A request, one json object per line.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct Request {
+    /// Must be [`VERSION`].
+    pub version: u32,
+    /// Picked by the client, the response carries it back.
+    pub id: u64,
+    /// What to do.
+    #[serde(flatten)]
+    pub operation: Operation,
+}
+
+/// What went wrong with a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorKind {
+    /// The request has another version.
+    UnsupportedVersion,
+    /// The request did not decode.
+    MalformedRequest,
+    /// The snapshot could not be written.
+    SnapshotFailed,
+    /// The operation could not be journaled, it did not take effect.
+    JournalFailed,
+    /// A poll asked for truncated messages.
+    OutOfRange,
+    /// The key does not exist.
+    KeyDoesNotExist,
+    /// A compare-and-set found another value.
+    PreconditionFailed,
+}
+
+impl ErrorKind {
+    /// The maelstrom error code clients should surface for this kind.
+    pub fn code(self) -> u64 {
+        match self {
+            Self::UnsupportedVersion => 10,
+            Self::MalformedRequest => 12,
+            Self::SnapshotFailed => 13,
+            Self::JournalFailed => 13,
+            Self::OutOfRange => 14,
+            Self::KeyDoesNotExist => 20,
+            Self::PreconditionFailed => 22,
+        }
+    }
+}
+
+/// Why a request failed.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct Error {
+    /// What went wrong.
+    pub kind: ErrorKind,
+    /// The maelstrom error code of `kind`.
+    pub code: u64,
+    /// A message for humans.
+    pub message: String,
+}
+
+impl Error {
+    /// An error of `kind`.
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            code: kind.code(),
+            message: message.into(),
+        }
+    }
+}
+
+impl From<crate::stores::OutOfRange> for Error {
+    fn from(value: crate::stores::OutOfRange) -> Self {
+        Self::new(ErrorKind::OutOfRange, value.to_string())
+    }
+}
+
+/// The result of a request, or why it failed.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// The result.
+    Result(serde_json::Value),
+    /// The error.
+    Error(Error),
+}
+
+/// The response to a request, responses may come out of order.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct Response {
+    /// Always [`VERSION`].
+    pub version: u32,
+    /// The id of the request.
+    pub id: u64,
+    /// The result or the error.
+    #[serde(flatten)]
+    pub outcome: Outcome,
+}
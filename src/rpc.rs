@@ -0,0 +1,373 @@
+//! The event loop: stdin is read on one thread and stdout written on another,
+//! while requests, replies and timers are handed to the node one at a time.
+
+/// Why a request got no reply.
+#[derive(Debug)]
+pub enum RpcError {
+    /// No reply arrived before the timeout.
+    Timeout,
+    /// Stdin closed before a reply arrived.
+    Disconnected,
+    /// The reply did not decode.
+    Malformed(serde_json::Error),
+}
+
+/// The reply to a request, before its type is decoded, or why there is none.
+pub type Reply = Result<crate::InputBody, RpcError>;
+
+/// Runs on the event loop with the reply to a request.
+pub type Callback<S> = Box<dyn FnOnce(&mut crate::contexts::Context<S>, Reply)>;
+
+/// Runs on the event loop once its delay elapsed.
+pub type Timer<S> = Box<dyn FnOnce(&mut crate::contexts::Context<S>)>;
+
+/// What happens with the reply to a request.
+pub enum Continuation<S> {
+    /// Run a callback with it.
+    Callback(Callback<S>),
+    /// Send it to a channel.
+    Channel(std::sync::mpsc::Sender<Reply>),
+}
+
+/// What the event loop hands to the node next.
+pub enum Event<S> {
+    /// A message that is not a reply.
+    Request(crate::Input),
+    /// A line that did not decode, with the error.
+    Malformed(String, serde_json::Error),
+    /// A callback that is ready to run with its reply.
+    Callback(Callback<S>, Reply),
+    /// A timer that is due.
+    Timer(Timer<S>),
+    /// Stdin closed, no request is pending and no timer is due.
+    Closed,
+}
+
+struct Pending<S> {
+    deadline: std::time::Instant,
+    continuation: Continuation<S>,
+}
+
+/// Tracks pending requests and timers and turns stdin into [`Event`]s.
+pub struct Rpc<S> {
+    inbox: std::sync::mpsc::Receiver<Vec<u8>>,
+    outbox: std::sync::mpsc::Sender<String>,
+    writer: Option<std::thread::JoinHandle<()>>,
+    closed: bool,
+    pending: std::collections::BTreeMap<usize, Pending<S>>,
+    timers: std::collections::BTreeMap<(std::time::Instant, usize), Timer<S>>,
+    timer_counter: usize,
+    ready: std::collections::VecDeque<Event<S>>,
+}
+
+impl<S> std::fmt::Debug for Rpc<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Rpc")
+            .field("closed", &self.closed)
+            .field("pending", &self.pending.keys().collect::<Vec<_>>())
+            .field("timers", &self.timers.len())
+            .field("ready", &self.ready.len())
+            .finish()
+    }
+}
+
+impl<S> Drop for Rpc<S> {
+    fn drop(&mut self) {
+        // the writer drains whatever is still queued once its last sender is gone
+        drop(std::mem::replace(
+            &mut self.outbox,
+            std::sync::mpsc::channel().0,
+        ));
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+impl<S> Rpc<S> {
+    /// An rpc that reads lines from `inbox` and writes to `outbox`. `writer` is
+    /// joined on drop so that queued messages are flushed.
+    pub fn new(
+        inbox: std::sync::mpsc::Receiver<Vec<u8>>,
+        outbox: std::sync::mpsc::Sender<String>,
+        writer: Option<std::thread::JoinHandle<()>>,
+    ) -> Self {
+        Self {
+            inbox,
+            outbox,
+            writer,
+            closed: false,
+            pending: std::collections::BTreeMap::new(),
+            timers: std::collections::BTreeMap::new(),
+            timer_counter: 0,
+            ready: std::collections::VecDeque::new(),
+        }
+    }
+
+    /// An rpc on channels, e.g. to test a node: it reads the lines sent on the
+    /// returned sender and writes to the returned receiver.
+    pub fn channels() -> (
+        Self,
+        std::sync::mpsc::Sender<Vec<u8>>,
+        std::sync::mpsc::Receiver<String>,
+    ) {
+        let (sender, inbox) = std::sync::mpsc::channel();
+        let (outbox, outgoing) = std::sync::mpsc::channel();
+        (Self::new(inbox, outbox, None), sender, outgoing)
+    }
+
+    /// An rpc on stdin and stdout.
+    pub fn stdio() -> Self {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        // lines are passed on as bytes so that one that is not valid utf-8 is
+        // rejected like any other malformed message instead of stopping the reader
+        std::thread::spawn(move || {
+            let mut stdin = std::io::stdin().lock();
+            loop {
+                let mut input_bytes = Vec::new();
+                match std::io::BufRead::read_until(&mut stdin, b'\n', &mut input_bytes) {
+                    Ok(0) => break,
+                    Ok(_) => {
+                        if sender.send(input_bytes).is_err() {
+                            break;
+                        }
+                    }
+                    Err(error) => {
+                        eprintln!("failed to read from stdin: {error}");
+                        break;
+                    }
+                }
+            }
+        });
+        // a single writer owns stdout so that messages are never interleaved
+        let (outbox, outgoing) = std::sync::mpsc::channel::<String>();
+        let writer = std::thread::spawn(move || {
+            let mut stdout = std::io::stdout().lock();
+            for output_string in outgoing {
+                std::io::Write::write_all(&mut stdout, output_string.as_bytes())
+                    .expect("failed to write to stdout");
+                std::io::Write::flush(&mut stdout).expect("failed to flush stdout");
+            }
+        });
+        Self::new(receiver, outbox, Some(writer))
+    }
+
+    /// Queues `output` to be written.
+    pub fn write<T>(&self, output: &crate::Output<T>)
+    where
+        T: serde::Serialize,
+    {
+        let output_string = serde_json::to_string(output).expect("failed to serialize output");
+        self.outbox
+            .send(format!("{output_string}\n"))
+            .expect("stdout writer stopped");
+    }
+
+    /// Writes the request `output` and resolves `continuation` with its reply,
+    /// or with [`RpcError::Timeout`] once `timeout` elapses.
+    pub fn call<T>(
+        &mut self,
+        output: crate::Output<T>,
+        timeout: std::time::Duration,
+        continuation: Continuation<S>,
+    ) where
+        T: serde::Serialize,
+    {
+        self.write(&output);
+        self.pending.insert(
+            output.body.msg_id,
+            Pending {
+                deadline: std::time::Instant::now() + timeout,
+                continuation,
+            },
+        );
+    }
+
+    /// Makes `timer` due after `delay`.
+    pub fn schedule(&mut self, delay: std::time::Duration, timer: Timer<S>) {
+        let deadline = std::time::Instant::now() + delay;
+        self.timers.insert((deadline, self.timer_counter), timer);
+        self.timer_counter += 1;
+    }
+
+    /// Waits for the next event.
+    pub fn next_event(&mut self) -> Event<S> {
+        loop {
+            if let Some(event) = self.ready.pop_front() {
+                return event;
+            }
+            if self.closed && self.pending.is_empty() {
+                // timers that are already due still run, e.g. to finish a reply
+                self.expire(std::time::Instant::now());
+                if self.ready.is_empty() {
+                    return Event::Closed;
+                }
+                continue;
+            }
+            self.pump();
+        }
+    }
+
+    fn resolve(&mut self, continuation: Continuation<S>, reply: Reply) {
+        match continuation {
+            Continuation::Callback(callback) => {
+                self.ready.push_back(Event::Callback(callback, reply))
+            }
+            Continuation::Channel(sender) => {
+                let _ = sender.send(reply);
+            }
+        }
+    }
+
+    fn expire(&mut self, now: std::time::Instant) {
+        let expired: Vec<usize> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.deadline <= now)
+            .map(|(msg_id, _)| *msg_id)
+            .collect();
+        for msg_id in expired {
+            let pending = self
+                .pending
+                .remove(&msg_id)
+                .expect("expired request is pending");
+            self.resolve(pending.continuation, Err(RpcError::Timeout));
+        }
+        while let Some(entry) = self.timers.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            self.ready.push_back(Event::Timer(entry.remove()));
+        }
+    }
+
+    fn dispatch(&mut self, input_bytes: Vec<u8>) {
+        let input: crate::Input = match serde_json::from_slice(&input_bytes) {
+            Ok(input) => input,
+            Err(error) => {
+                let input_string = String::from_utf8_lossy(&input_bytes).into_owned();
+                self.ready.push_back(Event::Malformed(input_string, error));
+                return;
+            }
+        };
+        let Some(in_reply_to) = input.body.in_reply_to else {
+            self.ready.push_back(Event::Request(input));
+            return;
+        };
+        match self.pending.remove(&in_reply_to) {
+            Some(pending) => self.resolve(pending.continuation, Ok(input.body)),
+            None => eprintln!("dropping reply to unknown request {in_reply_to}"),
+        }
+    }
+
+    fn pump(&mut self) {
+        if self.closed {
+            let pending = std::mem::take(&mut self.pending);
+            for (_, pending) in pending {
+                self.resolve(pending.continuation, Err(RpcError::Disconnected));
+            }
+            return;
+        }
+        let deadline = self
+            .pending
+            .values()
+            .map(|pending| pending.deadline)
+            .chain(self.timers.keys().map(|(deadline, _)| *deadline))
+            .min();
+        let received = match deadline {
+            Some(deadline) => self
+                .inbox
+                .recv_timeout(deadline.saturating_duration_since(std::time::Instant::now())),
+            None => self
+                .inbox
+                .recv()
+                .map_err(|_| std::sync::mpsc::RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok(input_bytes) => self.dispatch(input_bytes),
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {}
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => self.closed = true,
+        }
+        // deadlines are checked after every message, as a busy inbox never times out
+        self.expire(std::time::Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn keeps_serving_after_a_line_that_is_not_utf8() {
+        let (mut rpc, sender, _outgoing) = Rpc::<()>::channels();
+        sender
+            .send(b"{\"src\":\"c1\",\"dest\":\"n1\",\"body\":{\"type\":\"\xff\"}}\n".to_vec())
+            .unwrap();
+        sender
+            .send(b"{\"src\":\"c1\",\"dest\":\"n1\",\"body\":{\"type\":\"echo\"}}\n".to_vec())
+            .unwrap();
+        drop(sender);
+        assert!(matches!(rpc.next_event(), Event::Malformed(..)));
+        assert!(matches!(rpc.next_event(), Event::Request(input) if input.body.r#type == "echo"));
+        assert!(matches!(rpc.next_event(), Event::Closed));
+    }
+
+    fn request(msg_id: usize) -> crate::Output {
+        crate::Output {
+            src: "n1".to_string(),
+            dest: "lin-kv".to_string(),
+            body: crate::OutputBody {
+                msg_id,
+                in_reply_to: None,
+                typed_body: crate::TypedOutputBody::KvRead {
+                    key: "k".to_string(),
+                },
+            },
+        }
+    }
+
+    #[test]
+    fn routes_replies_by_in_reply_to() {
+        let (mut rpc, sender, _outgoing) = Rpc::<()>::channels();
+        let timeout = std::time::Duration::from_secs(10);
+        let (first, first_reply) = std::sync::mpsc::channel();
+        let (second, second_reply) = std::sync::mpsc::channel();
+        rpc.call(request(1), timeout, Continuation::Channel(first));
+        rpc.call(request(2), timeout, Continuation::Channel(second));
+        for line in [
+            r#"{"src":"lin-kv","dest":"n1","body":{"type":"read_ok","in_reply_to":2,"value":2}}"#,
+            r#"{"src":"lin-kv","dest":"n1","body":{"type":"read_ok","in_reply_to":3,"value":3}}"#,
+            r#"{"src":"lin-kv","dest":"n1","body":{"type":"read_ok","in_reply_to":1,"value":1}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":1}}"#,
+        ] {
+            sender.send(line.as_bytes().to_vec()).unwrap();
+        }
+        assert!(matches!(rpc.next_event(), Event::Request(_)));
+        for (reply, expected) in [(first_reply, 1), (second_reply, 2)] {
+            let reply = reply.try_recv().unwrap().unwrap().typed_body();
+            assert!(matches!(
+                reply,
+                Ok(crate::TypedInputBody::KvRead { value }) if value == expected
+            ));
+        }
+        assert!(rpc.pending.is_empty());
+    }
+
+    #[test]
+    fn times_out_requests_while_requests_are_queued() {
+        let (mut rpc, sender, _outgoing) = Rpc::<()>::channels();
+        let (continuation, reply) = std::sync::mpsc::channel();
+        let timeout = std::time::Duration::from_millis(1);
+        rpc.call(request(1), timeout, Continuation::Channel(continuation));
+        rpc.schedule(timeout, Box::new(|_| {}));
+        for msg_id in 0..100 {
+            let line =
+                format!(r#"{{"src":"c1","dest":"n1","body":{{"type":"echo","msg_id":{msg_id}}}}}"#);
+            sender.send(line.into_bytes()).unwrap();
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        assert!(matches!(rpc.next_event(), Event::Request(_)));
+        assert!(matches!(rpc.next_event(), Event::Timer(_)));
+        assert!(matches!(reply.try_recv().unwrap(), Err(RpcError::Timeout)));
+    }
+}
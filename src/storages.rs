@@ -0,0 +1,1132 @@
+//! Backends for the kafka and g-counter state: in memory, a kv-store server, or
+//! the maelstrom kv services.
+
+use crate::contexts::{KvError, LinKv, SeqKv};
+use crate::protocols::Operation;
+use crate::stores::{LogRetrieval, Offsets, DEFAULT_GROUP, DEFAULT_POLL_LIMIT};
+
+/// Why a storage operation failed.
+#[derive(Debug)]
+pub enum StorageError {
+    /// The kv-store could not be reached.
+    Unavailable(std::io::Error),
+    /// The connection to the kv-store failed.
+    Io(std::io::Error),
+    /// The kv-store sent a response that did not decode.
+    InvalidResponse(String),
+    /// The kv-store rejected the operation.
+    Rejected(crate::protocols::Error),
+    /// The wire protocol cannot express the operation.
+    Unsupported(String),
+    /// A maelstrom kv service failed.
+    Kv(KvError),
+}
+
+impl From<KvError> for StorageError {
+    fn from(value: KvError) -> Self {
+        Self::Kv(value)
+    }
+}
+
+// storages keep their data for nodes whose state is a backend
+type Context = crate::contexts::Context<Backend>;
+
+/// Runs on the event loop with the outcome of a storage operation.
+pub type Done<T> = Box<dyn FnOnce(&mut Context, Result<T, StorageError>)>;
+
+/// The operations the kafka and g-counter workloads need from their backend.
+/// Each one completes by calling `done` from the event loop, never before it
+/// returns, so that the node keeps serving requests while it waits.
+pub trait Storage: std::fmt::Debug {
+    /// Appends `msg` to the log of `key` and completes with its offset.
+    fn send(&mut self, context: &mut Context, key: String, msg: usize, done: Done<usize>);
+    /// Reads at most `limit` messages of each log, starting at its offset.
+    fn poll(
+        &mut self,
+        context: &mut Context,
+        offsets: Offsets,
+        limit: Option<usize>,
+        done: Done<LogRetrieval>,
+    );
+    /// Records that `group` processed each log up to its offset.
+    fn commit_offsets(
+        &mut self,
+        context: &mut Context,
+        group: String,
+        offsets: Offsets,
+        done: Done<()>,
+    );
+    /// The offsets `group` committed for `keys`, keys without one are left out.
+    fn list_committed_offsets(
+        &mut self,
+        context: &mut Context,
+        group: String,
+        keys: Vec<String>,
+        done: Done<Offsets>,
+    );
+    /// Every group that committed an offset.
+    fn list_groups(&mut self, context: &mut Context, done: Done<Vec<String>>);
+    /// How many messages `group` has yet to commit in each log it committed to.
+    fn group_lag(&mut self, context: &mut Context, group: String, done: Done<Offsets>);
+    /// The value of the global counter.
+    fn read_counter(&mut self, context: &mut Context, done: Done<usize>);
+    /// Adds `delta` to the global counter.
+    fn add_counter(&mut self, context: &mut Context, delta: usize, done: Done<()>);
+}
+
+// completes an operation that already has its outcome, see `Storage`
+fn finish<T>(context: &mut Context, result: Result<T, StorageError>, done: Done<T>)
+where
+    T: 'static,
+{
+    context.schedule(std::time::Duration::ZERO, move |context| {
+        done(context, result)
+    })
+}
+
+/// The kv-store named by `KV_BACKEND=kv-store`, which then stands in for the
+/// maelstrom kv services, e.g. to run workloads without maelstrom.
+pub fn kv_from_env() -> Option<TcpStorage> {
+    match std::env::var("KV_BACKEND").as_deref() {
+        Ok("maelstrom") | Err(_) => None,
+        Ok("kv-store") => Some(TcpStorage::new(server_endpoint(), WireProtocol::Json)),
+        Ok(backend) => panic!("unknown KV_BACKEND {backend}, expect maelstrom or kv-store"),
+    }
+}
+
+/// The backend named by `STORAGE_BACKEND`, maelstrom by default.
+pub fn from_env() -> Box<dyn Storage> {
+    match std::env::var("STORAGE_BACKEND").as_deref() {
+        Ok("maelstrom") | Err(_) => Box::new(MaelstromStorage),
+        Ok("kv-store") => Box::new(TcpStorage::new(server_endpoint(), WireProtocol::from_env())),
+        Ok("memory") => Box::new(MemoryStorage::default()),
+        Ok(backend) => {
+            panic!("unknown STORAGE_BACKEND {backend}, expect maelstrom, kv-store or memory")
+        }
+    }
+}
+
+/// The state of a node that keeps its data in a [`Storage`], e.g. a kafka or
+/// g-counter node.
+#[derive(Debug)]
+pub struct Backend {
+    storage: Option<Box<dyn Storage>>,
+}
+
+impl Backend {
+    /// A backend that keeps its data in `storage`.
+    pub fn new(storage: Box<dyn Storage>) -> Self {
+        Self {
+            storage: Some(storage),
+        }
+    }
+}
+
+/// Lends the storage of the node to `f`, along with the context it needs for
+/// the maelstrom kv services.
+pub fn with_storage<T>(
+    context: &mut Context,
+    f: impl FnOnce(&mut dyn Storage, &mut Context) -> T,
+) -> T {
+    let mut storage = context
+        .state
+        .storage
+        .take()
+        .expect("storage is not re-entrant");
+    let result = f(storage.as_mut(), context);
+    context.state.storage = Some(storage);
+    result
+}
+
+const GLOBAL_COUNTER_KEY: &str = "g-counter";
+const GROUPS_KEY: &str = "groups";
+
+/// Keeps everything on this node, which is only correct for a single node.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    store: crate::stores::Store,
+}
+
+impl Storage for MemoryStorage {
+    fn send(&mut self, context: &mut Context, key: String, msg: usize, done: Done<usize>) {
+        let offset = self.store.send(key, msg, crate::stores::now());
+        finish(context, Ok(offset), done)
+    }
+
+    fn poll(
+        &mut self,
+        context: &mut Context,
+        offsets: Offsets,
+        limit: Option<usize>,
+        done: Done<LogRetrieval>,
+    ) {
+        let result = self
+            .store
+            .poll(offsets, limit.unwrap_or(DEFAULT_POLL_LIMIT))
+            .map_err(|error| StorageError::Rejected(error.into()));
+        finish(context, result, done)
+    }
+
+    fn commit_offsets(
+        &mut self,
+        context: &mut Context,
+        group: String,
+        offsets: Offsets,
+        done: Done<()>,
+    ) {
+        self.store.commit_offsets(group, offsets);
+        finish(context, Ok(()), done)
+    }
+
+    fn list_committed_offsets(
+        &mut self,
+        context: &mut Context,
+        group: String,
+        keys: Vec<String>,
+        done: Done<Offsets>,
+    ) {
+        let offsets = self.store.list_committed_offsets(group.as_str(), keys);
+        finish(context, Ok(offsets), done)
+    }
+
+    fn list_groups(&mut self, context: &mut Context, done: Done<Vec<String>>) {
+        finish(context, Ok(self.store.groups()), done)
+    }
+
+    fn group_lag(&mut self, context: &mut Context, group: String, done: Done<Offsets>) {
+        finish(context, Ok(self.store.lag(group.as_str())), done)
+    }
+
+    fn read_counter(&mut self, context: &mut Context, done: Done<usize>) {
+        finish(
+            context,
+            Ok(self.store.counter_read(GLOBAL_COUNTER_KEY)),
+            done,
+        )
+    }
+
+    fn add_counter(&mut self, context: &mut Context, delta: usize, done: Done<()>) {
+        self.store
+            .counter_add(GLOBAL_COUNTER_KEY.to_string(), delta);
+        finish(context, Ok(()), done)
+    }
+}
+
+// --kv-store-endpoint wins over the environment variable, which wins over the default
+fn server_endpoint() -> crate::endpoints::Endpoint {
+    std::env::args()
+        .skip_while(|argument| argument != "--kv-store-endpoint")
+        .nth(1)
+        .or_else(|| std::env::var(crate::endpoints::ENDPOINT_VARIABLE).ok())
+        .unwrap_or_else(|| crate::endpoints::DEFAULT_ENDPOINT.to_string())
+        .parse()
+        .unwrap_or_else(|error| panic!("invalid kv-store endpoint: {error}"))
+}
+
+/// How a [`TcpStorage`] talks to the kv-store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireProtocol {
+    /// Framed json requests, see [`crate::protocols`].
+    Json,
+    /// The `command:key:value::` text format, one request per connection.
+    Legacy,
+}
+
+impl WireProtocol {
+    fn from_env() -> Self {
+        match std::env::var("KV_STORE_PROTOCOL").as_deref() {
+            Ok("json") | Err(_) => Self::Json,
+            Ok("legacy") => Self::Legacy,
+            Ok(protocol) => panic!("unknown KV_STORE_PROTOCOL {protocol}, expect json or legacy"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Connection {
+    writer: crate::endpoints::Stream,
+    reader: std::io::BufReader<crate::endpoints::Stream>,
+}
+
+impl Connection {
+    fn open(
+        endpoint: &crate::endpoints::Endpoint,
+        timeout: std::time::Duration,
+    ) -> Result<Self, StorageError> {
+        let writer = endpoint.connect().map_err(StorageError::Unavailable)?;
+        // a stuck kv-store must not block the event loop forever
+        writer
+            .set_timeout(Some(timeout))
+            .map_err(StorageError::Io)?;
+        let reader = std::io::BufReader::new(writer.try_clone().map_err(StorageError::Io)?);
+        Ok(Self { writer, reader })
+    }
+
+    // writes every request before reading any response, responses are matched back by id.
+    // a connection the kv-store closed, e.g. as it restarted, fails the write or closes
+    // before the first response, which is reported as unavailable rather than as i/o
+    fn pipeline(
+        &mut self,
+        requests: &[crate::protocols::Request],
+    ) -> Result<std::collections::BTreeMap<u64, crate::protocols::Response>, StorageError> {
+        let mut inputs = String::new();
+        for request in requests {
+            inputs.push_str(
+                serde_json::to_string(request)
+                    .expect("failed to serialize")
+                    .as_str(),
+            );
+            inputs.push('\n');
+        }
+        std::io::Write::write_all(&mut self.writer, inputs.as_bytes())
+            .and_then(|_| std::io::Write::flush(&mut self.writer))
+            .map_err(StorageError::Unavailable)?;
+        let mut responses = std::collections::BTreeMap::new();
+        while responses.len() < requests.len() {
+            let mut response = String::new();
+            let read = std::io::BufRead::read_line(&mut self.reader, &mut response).and_then(
+                |read_size| match read_size {
+                    0 => Err(std::io::ErrorKind::UnexpectedEof.into()),
+                    read_size => Ok(read_size),
+                },
+            );
+            match read {
+                Ok(_) => {}
+                Err(error)
+                    if responses.is_empty()
+                        && matches!(
+                            error.kind(),
+                            std::io::ErrorKind::UnexpectedEof | std::io::ErrorKind::ConnectionReset
+                        ) =>
+                {
+                    return Err(StorageError::Unavailable(error))
+                }
+                Err(error) => return Err(StorageError::Io(error)),
+            }
+            let response: crate::protocols::Response =
+                TcpStorage::deserialize_response(response.trim().to_string())?;
+            responses.insert(response.id, response);
+        }
+        Ok(responses)
+    }
+}
+
+const CONNECTION_POOL_CAPACITY: usize = 4;
+const CONNECTION_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+
+#[derive(Debug)]
+struct ConnectionPool {
+    endpoint: crate::endpoints::Endpoint,
+    timeout: std::time::Duration,
+    idle: Vec<Connection>,
+}
+
+impl ConnectionPool {
+    // a connection that failed is dropped rather than checked back in, as it
+    // may still carry responses to requests that were given up on
+    fn pipeline(
+        &mut self,
+        requests: &[crate::protocols::Request],
+    ) -> Result<std::collections::BTreeMap<u64, crate::protocols::Response>, StorageError> {
+        if let Some(mut connection) = self.idle.pop() {
+            match connection.pipeline(requests) {
+                Ok(responses) => {
+                    self.checkin(connection);
+                    return Ok(responses);
+                }
+                // the pooled connection went stale, retry once on a fresh one
+                Err(StorageError::Unavailable(error)) => {
+                    eprintln!("dropping a stale kv-store connection: {error}");
+                    self.idle.clear();
+                }
+                Err(error) => return Err(error),
+            }
+        }
+        let mut connection = Connection::open(&self.endpoint, self.timeout)?;
+        let responses = connection.pipeline(requests)?;
+        self.checkin(connection);
+        Ok(responses)
+    }
+
+    fn checkin(&mut self, connection: Connection) {
+        if self.idle.len() < CONNECTION_POOL_CAPACITY {
+            self.idle.push(connection)
+        }
+    }
+}
+
+/// A client for a kv-store server.
+#[derive(Debug)]
+pub struct TcpStorage {
+    protocol: WireProtocol,
+    pool: ConnectionPool,
+    request_counter: u64,
+}
+
+impl TcpStorage {
+    /// A client for the kv-store at `endpoint`, connections are opened on demand.
+    pub fn new(endpoint: crate::endpoints::Endpoint, protocol: WireProtocol) -> Self {
+        Self {
+            protocol,
+            pool: ConnectionPool {
+                endpoint,
+                timeout: CONNECTION_TIMEOUT,
+                idle: Vec::new(),
+            },
+            request_counter: 0,
+        }
+    }
+
+    /// Gives up on reads and writes that block for longer than `timeout`.
+    pub fn with_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.pool.timeout = timeout;
+        self
+    }
+
+    fn legacy_request(&self, inputs: String) -> Result<String, StorageError> {
+        let mut socket = self
+            .pool
+            .endpoint
+            .connect()
+            .map_err(StorageError::Unavailable)?;
+        socket
+            .set_timeout(Some(self.pool.timeout))
+            .map_err(StorageError::Io)?;
+        std::io::Write::write(&mut socket, format!("{inputs}\r\n").to_string().as_bytes())
+            .map_err(StorageError::Io)?;
+        std::io::Write::flush(&mut socket).map_err(StorageError::Io)?;
+        let mut response = String::new();
+        std::io::Read::read_to_string(&mut socket, &mut response).map_err(StorageError::Io)?;
+        Ok(response.trim().to_string())
+    }
+
+    fn deserialize_response<T>(response: String) -> Result<T, StorageError>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        serde_json::from_str(response.as_str()).map_err(|_| StorageError::InvalidResponse(response))
+    }
+
+    fn serialize(offsets: &Offsets) -> String {
+        offsets
+            .iter()
+            .map(|(key, value)| format!("{key}:{value}"))
+            .collect::<Vec<String>>()
+            .join(":")
+    }
+
+    fn legacy_encode(operation: &Operation) -> Result<String, StorageError> {
+        match operation {
+            Operation::Send { key, msg } => Ok(format!("send:{key}:{msg}")),
+            // the legacy format has no limit, the server applies its default
+            Operation::Poll { offsets, .. } => Ok(format!("poll:{}::", Self::serialize(offsets))),
+            Operation::CommitOffsets { offsets, group } if group == DEFAULT_GROUP => {
+                Ok(format!("commit-offsets:{}::", Self::serialize(offsets)))
+            }
+            Operation::ListCommittedOffsets { keys, group } if group == DEFAULT_GROUP => {
+                Ok(format!("list-committed-offsets:{}::", keys.join(":")))
+            }
+            Operation::CounterRead { key } => Ok(format!("counter-read:{key}")),
+            Operation::CounterAdd { key, delta } => Ok(format!("counter-add:{key}:{delta}")),
+            Operation::Snapshot => Ok("snapshot:".to_string()),
+            Operation::CommitOffsets { .. }
+            | Operation::ListCommittedOffsets { .. }
+            | Operation::ListGroups
+            | Operation::GroupLag { .. } => Err(StorageError::Unsupported(
+                "consumer groups are not supported by the legacy protocol".to_string(),
+            )),
+            Operation::SetRetention { .. } | Operation::Truncate { .. } => {
+                Err(StorageError::Unsupported(
+                    "retention is not supported by the legacy protocol".to_string(),
+                ))
+            }
+            Operation::Read { .. } | Operation::Write { .. } | Operation::Cas { .. } => {
+                Err(StorageError::Unsupported(
+                    "key-value operations are not supported by the legacy protocol".to_string(),
+                ))
+            }
+        }
+    }
+
+    /// Runs `operation` on the kv-store and decodes its result.
+    pub fn execute<T>(&mut self, operation: Operation) -> Result<T, StorageError>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        self.execute_many(vec![operation])?
+            .pop()
+            .expect("one outcome per operation")
+    }
+
+    /// Runs independent `operations` on the kv-store, pipelined on one
+    /// connection. Each operation has its own outcome, in the order of
+    /// `operations`, while a broken connection fails the whole batch.
+    pub fn execute_many<T>(
+        &mut self,
+        operations: Vec<Operation>,
+    ) -> Result<Vec<Result<T, StorageError>>, StorageError>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        if self.protocol == WireProtocol::Legacy {
+            // a legacy connection answers a single request
+            return Ok(operations
+                .iter()
+                .map(|operation| {
+                    let response = self.legacy_request(Self::legacy_encode(operation)?)?;
+                    if response.is_empty() {
+                        return Self::deserialize_response("null".to_string());
+                    }
+                    Self::deserialize_response(response)
+                })
+                .collect());
+        }
+        let requests = operations
+            .into_iter()
+            .map(|operation| {
+                let id = self.request_counter;
+                self.request_counter += 1;
+                crate::protocols::Request {
+                    version: crate::protocols::VERSION,
+                    id,
+                    operation,
+                }
+            })
+            .collect::<Vec<_>>();
+        if requests.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut responses = self.pool.pipeline(&requests)?;
+        Ok(requests
+            .iter()
+            .map(|request| {
+                let Some(response) = responses.remove(&request.id) else {
+                    return Err(StorageError::InvalidResponse(format!(
+                        "missing response to request {}",
+                        request.id
+                    )));
+                };
+                match response.outcome {
+                    crate::protocols::Outcome::Result(value) => {
+                        serde_json::from_value(value.clone())
+                            .map_err(|_| StorageError::InvalidResponse(value.to_string()))
+                    }
+                    crate::protocols::Outcome::Error(error) => Err(StorageError::Rejected(error)),
+                }
+            })
+            .collect())
+    }
+}
+
+// the kv-store answers on the spot, so operations block the node until it does
+impl Storage for TcpStorage {
+    fn send(&mut self, context: &mut Context, key: String, msg: usize, done: Done<usize>) {
+        finish(context, self.execute(Operation::Send { key, msg }), done)
+    }
+
+    fn poll(
+        &mut self,
+        context: &mut Context,
+        offsets: Offsets,
+        limit: Option<usize>,
+        done: Done<LogRetrieval>,
+    ) {
+        finish(
+            context,
+            self.execute(Operation::Poll { offsets, limit }),
+            done,
+        )
+    }
+
+    fn commit_offsets(
+        &mut self,
+        context: &mut Context,
+        group: String,
+        offsets: Offsets,
+        done: Done<()>,
+    ) {
+        let result = self.execute(Operation::CommitOffsets { offsets, group });
+        finish(context, result, done)
+    }
+
+    fn list_committed_offsets(
+        &mut self,
+        context: &mut Context,
+        group: String,
+        keys: Vec<String>,
+        done: Done<Offsets>,
+    ) {
+        let result = self.execute(Operation::ListCommittedOffsets { keys, group });
+        finish(context, result, done)
+    }
+
+    fn list_groups(&mut self, context: &mut Context, done: Done<Vec<String>>) {
+        finish(context, self.execute(Operation::ListGroups), done)
+    }
+
+    fn group_lag(&mut self, context: &mut Context, group: String, done: Done<Offsets>) {
+        finish(context, self.execute(Operation::GroupLag { group }), done)
+    }
+
+    fn read_counter(&mut self, context: &mut Context, done: Done<usize>) {
+        let result = self.execute(Operation::CounterRead {
+            key: GLOBAL_COUNTER_KEY.to_string(),
+        });
+        finish(context, result, done)
+    }
+
+    fn add_counter(&mut self, context: &mut Context, delta: usize, done: Done<()>) {
+        let result = self.execute::<usize>(Operation::CounterAdd {
+            key: GLOBAL_COUNTER_KEY.to_string(),
+            delta,
+        });
+        finish(context, result.map(|_| ()), done)
+    }
+}
+
+/// Stores logs and offsets in lin-kv and the counter in seq-kv.
+#[derive(Debug)]
+pub struct MaelstromStorage;
+
+// every read-modify-write is a cas that is retried from the read when it
+// loses a race, each step running in the callback of the one before
+impl MaelstromStorage {
+    fn log_key(key: &str) -> String {
+        format!("log-{key}")
+    }
+
+    // the group is quoted so that it cannot run into the key
+    fn offset_key(group: &str, key: &str) -> String {
+        format!("offset-{group:?}-{key}")
+    }
+
+    fn group_keys_key(group: &str) -> String {
+        format!("keys-{group:?}")
+    }
+
+    fn read_or<V>(result: Result<V, KvError>, default: V) -> Result<V, KvError> {
+        match result {
+            Err(KvError::KeyDoesNotExist) => Ok(default),
+            result => result,
+        }
+    }
+
+    fn insert_into_set(context: &mut Context, key: String, value: String, done: Done<()>) {
+        context
+            .kv::<LinKv>()
+            .read(&key.clone(), move |context, set: Result<Vec<String>, _>| {
+                let set = match Self::read_or(set, Vec::new()) {
+                    Ok(set) => set,
+                    Err(error) => return done(context, Err(error.into())),
+                };
+                if set.contains(&value) {
+                    return done(context, Ok(()));
+                }
+                let mut inserted = set.clone();
+                inserted.push(value.clone());
+                context.kv::<LinKv>().cas(
+                    &key.clone(),
+                    set,
+                    inserted,
+                    true,
+                    move |context, result| match result {
+                        Ok(()) => done(context, Ok(())),
+                        Err(KvError::PreconditionFailed) => {
+                            Self::insert_into_set(context, key, value, done)
+                        }
+                        Err(error) => done(context, Err(error.into())),
+                    },
+                )
+            })
+    }
+
+    fn append(context: &mut Context, key: String, msg: usize, done: Done<usize>) {
+        context
+            .kv::<LinKv>()
+            .read(&key.clone(), move |context, log: Result<Vec<usize>, _>| {
+                let log = match Self::read_or(log, Vec::new()) {
+                    Ok(log) => log,
+                    Err(error) => return done(context, Err(error.into())),
+                };
+                let offset = log.len();
+                let mut appended = log.clone();
+                appended.push(msg);
+                context.kv::<LinKv>().cas(
+                    &key.clone(),
+                    log,
+                    appended,
+                    true,
+                    move |context, result| match result {
+                        Ok(()) => done(context, Ok(offset)),
+                        Err(KvError::PreconditionFailed) => Self::append(context, key, msg, done),
+                        Err(error) => done(context, Err(error.into())),
+                    },
+                )
+            })
+    }
+
+    fn retrieve(
+        offsets: Offsets,
+        limit: Option<usize>,
+        logs: Result<Vec<Result<Vec<usize>, KvError>>, KvError>,
+    ) -> Result<LogRetrieval, StorageError> {
+        let mut retrieved = LogRetrieval::new();
+        for ((key, offset), log) in offsets.into_iter().zip(logs?) {
+            let log = Self::read_or(log, Vec::new())?;
+            retrieved.insert(
+                key,
+                log.into_iter()
+                    .enumerate()
+                    .skip(offset)
+                    .take(limit.unwrap_or(DEFAULT_POLL_LIMIT))
+                    .collect(),
+            );
+        }
+        Ok(retrieved)
+    }
+
+    // commits one offset after the other
+    fn commit_each(context: &mut Context, group: String, mut offsets: Offsets, done: Done<()>) {
+        let Some((key, offset)) = offsets.pop_first() else {
+            return done(context, Ok(()));
+        };
+        Self::commit(
+            context,
+            group.clone(),
+            key,
+            offset,
+            Box::new(move |context, result| match result {
+                Ok(()) => Self::commit_each(context, group, offsets, done),
+                Err(error) => done(context, Err(error)),
+            }),
+        )
+    }
+
+    // raises the offset `group` committed for `key` to `offset`, unless it is higher already
+    fn commit(context: &mut Context, group: String, key: String, offset: usize, done: Done<()>) {
+        let offset_key = Self::offset_key(group.as_str(), key.as_str());
+        context.kv::<LinKv>().read(
+            &offset_key.clone(),
+            move |context, committed: Result<usize, _>| {
+                let committed = match Self::read_or(committed.map(Some), None) {
+                    Ok(committed) => committed,
+                    Err(error) => return done(context, Err(error.into())),
+                };
+                match committed {
+                    Some(committed) if committed >= offset => done(context, Ok(())),
+                    Some(committed) => context.kv::<LinKv>().cas(
+                        &offset_key,
+                        committed,
+                        offset,
+                        false,
+                        move |context, result| {
+                            Self::retry_commit(context, result, group, key, offset, done)
+                        },
+                    ),
+                    // register the key before its first offset so that lag never misses it
+                    None => Self::register(
+                        context,
+                        group.clone(),
+                        key.clone(),
+                        Box::new(move |context, result| match result {
+                            Ok(()) => context.kv::<LinKv>().cas(
+                                &offset_key,
+                                offset,
+                                offset,
+                                true,
+                                move |context, result| {
+                                    Self::retry_commit(context, result, group, key, offset, done)
+                                },
+                            ),
+                            Err(error) => done(context, Err(error)),
+                        }),
+                    ),
+                }
+            },
+        )
+    }
+
+    fn retry_commit(
+        context: &mut Context,
+        result: Result<(), KvError>,
+        group: String,
+        key: String,
+        offset: usize,
+        done: Done<()>,
+    ) {
+        match result {
+            Ok(()) => done(context, Ok(())),
+            Err(KvError::PreconditionFailed) => Self::commit(context, group, key, offset, done),
+            Err(error) => done(context, Err(error.into())),
+        }
+    }
+
+    fn register(context: &mut Context, group: String, key: String, done: Done<()>) {
+        Self::insert_into_set(
+            context,
+            Self::group_keys_key(group.as_str()),
+            key,
+            Box::new(move |context, result| match result {
+                Ok(()) => Self::insert_into_set(context, GROUPS_KEY.to_string(), group, done),
+                Err(error) => done(context, Err(error)),
+            }),
+        )
+    }
+
+    fn committed_offsets(
+        context: &mut Context,
+        group: &str,
+        keys: Vec<String>,
+        done: Done<Offsets>,
+    ) {
+        let offset_keys = keys
+            .iter()
+            .map(|key| Self::offset_key(group, key.as_str()))
+            .collect::<Vec<_>>();
+        context
+            .kv::<LinKv>()
+            .read_many(&offset_keys, move |context, committed_offsets| {
+                done(context, Self::collect_offsets(keys, committed_offsets))
+            })
+    }
+
+    fn collect_offsets(
+        keys: Vec<String>,
+        committed_offsets: Result<Vec<Result<usize, KvError>>, KvError>,
+    ) -> Result<Offsets, StorageError> {
+        let mut offsets = Offsets::new();
+        for (key, committed) in keys.into_iter().zip(committed_offsets?) {
+            if let Some(committed) = Self::read_or(committed.map(Some), None)? {
+                offsets.insert(key, committed);
+            }
+        }
+        Ok(offsets)
+    }
+
+    fn lag(
+        committed_offsets: Offsets,
+        logs: Result<Vec<Result<Vec<usize>, KvError>>, KvError>,
+    ) -> Result<Offsets, StorageError> {
+        let mut lag = Offsets::new();
+        for ((key, committed_offset), log) in committed_offsets.into_iter().zip(logs?) {
+            let log = Self::read_or(log, Vec::new())?;
+            lag.insert(key, log.len().saturating_sub(committed_offset + 1));
+        }
+        Ok(lag)
+    }
+
+    fn confirm_counter(context: &mut Context, done: Done<usize>) {
+        context
+            .kv::<SeqKv>()
+            .read(GLOBAL_COUNTER_KEY, move |context, value| {
+                let value = match Self::read_or(value, 0) {
+                    Ok(value) => value,
+                    Err(error) => return done(context, Err(error.into())),
+                };
+                // a sequentially consistent read may be stale, confirm it with a no-op cas
+                context.kv::<SeqKv>().cas(
+                    GLOBAL_COUNTER_KEY,
+                    value,
+                    value,
+                    true,
+                    move |context, result| match result {
+                        Ok(()) => done(context, Ok(value)),
+                        Err(KvError::PreconditionFailed) => Self::confirm_counter(context, done),
+                        Err(error) => done(context, Err(error.into())),
+                    },
+                )
+            })
+    }
+
+    fn add_to_counter(context: &mut Context, delta: usize, done: Done<()>) {
+        context.kv::<SeqKv>().read(
+            GLOBAL_COUNTER_KEY,
+            move |context, value: Result<usize, _>| {
+                let value = match Self::read_or(value, 0) {
+                    Ok(value) => value,
+                    Err(error) => return done(context, Err(error.into())),
+                };
+                context.kv::<SeqKv>().cas(
+                    GLOBAL_COUNTER_KEY,
+                    value,
+                    value + delta,
+                    true,
+                    move |context, result| match result {
+                        Ok(()) => done(context, Ok(())),
+                        Err(KvError::PreconditionFailed) => {
+                            Self::add_to_counter(context, delta, done)
+                        }
+                        Err(error) => done(context, Err(error.into())),
+                    },
+                )
+            },
+        )
+    }
+}
+
+impl Storage for MaelstromStorage {
+    fn send(&mut self, context: &mut Context, key: String, msg: usize, done: Done<usize>) {
+        Self::append(context, Self::log_key(key.as_str()), msg, done)
+    }
+
+    fn poll(
+        &mut self,
+        context: &mut Context,
+        offsets: Offsets,
+        limit: Option<usize>,
+        done: Done<LogRetrieval>,
+    ) {
+        let log_keys = offsets
+            .keys()
+            .map(|key| Self::log_key(key.as_str()))
+            .collect::<Vec<_>>();
+        context
+            .kv::<LinKv>()
+            .read_many(&log_keys, move |context, logs| {
+                done(context, Self::retrieve(offsets, limit, logs))
+            })
+    }
+
+    fn commit_offsets(
+        &mut self,
+        context: &mut Context,
+        group: String,
+        offsets: Offsets,
+        done: Done<()>,
+    ) {
+        if offsets.is_empty() {
+            return finish(context, Ok(()), done);
+        }
+        Self::commit_each(context, group, offsets, done)
+    }
+
+    fn list_committed_offsets(
+        &mut self,
+        context: &mut Context,
+        group: String,
+        keys: Vec<String>,
+        done: Done<Offsets>,
+    ) {
+        Self::committed_offsets(context, group.as_str(), keys, done)
+    }
+
+    fn list_groups(&mut self, context: &mut Context, done: Done<Vec<String>>) {
+        context
+            .kv::<LinKv>()
+            .read(GROUPS_KEY, move |context, groups| {
+                done(
+                    context,
+                    Self::read_or(groups, Vec::new()).map_err(StorageError::from),
+                )
+            })
+    }
+
+    fn group_lag(&mut self, context: &mut Context, group: String, done: Done<Offsets>) {
+        let keys_key = Self::group_keys_key(group.as_str());
+        context.kv::<LinKv>().read(&keys_key, move |context, keys| {
+            let keys = match Self::read_or(keys, Vec::new()) {
+                Ok(keys) => keys,
+                Err(error) => return done(context, Err(error.into())),
+            };
+            Self::committed_offsets(
+                context,
+                group.as_str(),
+                keys,
+                Box::new(move |context, committed_offsets| {
+                    let committed_offsets = match committed_offsets {
+                        Ok(committed_offsets) => committed_offsets,
+                        Err(error) => return done(context, Err(error)),
+                    };
+                    let log_keys = committed_offsets
+                        .keys()
+                        .map(|key| Self::log_key(key.as_str()))
+                        .collect::<Vec<_>>();
+                    context
+                        .kv::<LinKv>()
+                        .read_many(&log_keys, move |context, logs| {
+                            done(context, Self::lag(committed_offsets, logs))
+                        })
+                }),
+            )
+        })
+    }
+
+    fn read_counter(&mut self, context: &mut Context, done: Done<usize>) {
+        Self::confirm_counter(context, done)
+    }
+
+    fn add_counter(&mut self, context: &mut Context, delta: usize, done: Done<()>) {
+        Self::add_to_counter(context, delta, done)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // a kv-store stand-in on a fresh unix socket that runs `serve` on each connection
+    fn server(
+        name: &str,
+        serve: impl Fn(std::os::unix::net::UnixStream) + Send + 'static,
+    ) -> crate::endpoints::Endpoint {
+        let path =
+            std::env::temp_dir().join(format!("storages-{}-{name}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                serve(stream.unwrap());
+            }
+        });
+        crate::endpoints::Endpoint::Unix(path)
+    }
+
+    #[test]
+    fn reconnects_once_the_kv_store_closed_a_pooled_connection() {
+        // answers a single request per connection, like a server that restarts in between
+        let endpoint = server("restart", |stream| {
+            let mut reader = std::io::BufReader::new(&stream);
+            let mut request_string = String::new();
+            std::io::BufRead::read_line(&mut reader, &mut request_string).unwrap();
+            let request: crate::protocols::Request = serde_json::from_str(&request_string).unwrap();
+            let response = crate::protocols::Response {
+                version: crate::protocols::VERSION,
+                id: request.id,
+                outcome: crate::protocols::Outcome::Result(7.into()),
+            };
+            let response_string = serde_json::to_string(&response).unwrap();
+            std::io::Write::write_all(&mut &stream, format!("{response_string}\n").as_bytes())
+                .unwrap();
+        });
+        let mut storage = TcpStorage::new(endpoint, WireProtocol::Json);
+        for _ in 0..3 {
+            let value: usize = storage
+                .execute(Operation::CounterRead {
+                    key: "c".to_string(),
+                })
+                .unwrap();
+            assert_eq!(value, 7);
+        }
+    }
+
+    #[test]
+    fn times_out_a_kv_store_that_never_responds() {
+        let (sender, streams) = std::sync::mpsc::channel();
+        let endpoint = server("stuck", move |stream| sender.send(stream).unwrap());
+        let mut storage = TcpStorage::new(endpoint, WireProtocol::Json)
+            .with_timeout(std::time::Duration::from_millis(10));
+        let error = storage
+            .execute::<usize>(Operation::CounterRead {
+                key: "c".to_string(),
+            })
+            .unwrap_err();
+        assert_eq!(
+            crate::errors::Error::from(error).code,
+            crate::errors::ErrorCode::Timeout
+        );
+        drop(streams);
+    }
+
+    // an initialized node on channels that keeps the counter in seq-kv
+    fn maelstrom() -> (
+        Context,
+        std::sync::mpsc::Sender<Vec<u8>>,
+        std::sync::mpsc::Receiver<String>,
+    ) {
+        let (mut context, sender, outgoing) =
+            crate::contexts::test::context(Backend::new(Box::new(MaelstromStorage)));
+        context
+            .initialize("n1".to_string(), vec!["n1".to_string()])
+            .unwrap();
+        (context, sender, outgoing)
+    }
+
+    // answers the request the node sent last with `body` and runs its callback
+    fn answer(
+        context: &mut Context,
+        sender: &std::sync::mpsc::Sender<Vec<u8>>,
+        outgoing: &std::sync::mpsc::Receiver<String>,
+        mut body: serde_json::Value,
+    ) -> serde_json::Value {
+        let mut request: serde_json::Value =
+            serde_json::from_str(&outgoing.try_recv().unwrap()).unwrap();
+        body["in_reply_to"] = request["body"]["msg_id"].clone();
+        let line = serde_json::json!({"src": request["dest"], "dest": "n1", "body": body});
+        sender.send(line.to_string().into_bytes()).unwrap();
+        crate::contexts::test::run_next(context);
+        request["body"].take()
+    }
+
+    #[test]
+    fn adds_to_the_counter_again_when_a_cas_loses_a_race() {
+        let (mut context, sender, outgoing) = maelstrom();
+        let added = std::rc::Rc::new(std::cell::Cell::new(None));
+        let outcome = added.clone();
+        with_storage(&mut context, |storage, context| {
+            storage.add_counter(
+                context,
+                2,
+                Box::new(move |_, result| outcome.set(Some(result.is_ok()))),
+            )
+        });
+        let read = serde_json::json!({"type": "read_ok", "value": 3});
+        assert_eq!(
+            answer(&mut context, &sender, &outgoing, read)["type"],
+            "read"
+        );
+        let lost = serde_json::json!({"type": "error", "code": 22});
+        let cas = answer(&mut context, &sender, &outgoing, lost);
+        assert_eq!((&cas["from"], &cas["to"]), (&3.into(), &5.into()));
+        assert_eq!(added.get(), None);
+        let read = serde_json::json!({"type": "read_ok", "value": 4});
+        assert_eq!(
+            answer(&mut context, &sender, &outgoing, read)["type"],
+            "read"
+        );
+        let cas = answer(
+            &mut context,
+            &sender,
+            &outgoing,
+            serde_json::json!({"type": "cas_ok"}),
+        );
+        assert_eq!((&cas["from"], &cas["to"]), (&4.into(), &6.into()));
+        assert_eq!(added.get(), Some(true));
+        assert!(outgoing.try_recv().is_err());
+    }
+
+    #[test]
+    fn creates_the_counter_when_it_is_first_read() {
+        let (mut context, sender, outgoing) = maelstrom();
+        let value = std::rc::Rc::new(std::cell::Cell::new(None));
+        let outcome = value.clone();
+        with_storage(&mut context, |storage, context| {
+            storage.read_counter(
+                context,
+                Box::new(move |_, result| outcome.set(Some(result.unwrap()))),
+            )
+        });
+        let missing = serde_json::json!({"type": "error", "code": 20});
+        assert_eq!(
+            answer(&mut context, &sender, &outgoing, missing)["type"],
+            "read"
+        );
+        let cas = answer(
+            &mut context,
+            &sender,
+            &outgoing,
+            serde_json::json!({"type": "cas_ok"}),
+        );
+        assert_eq!(cas["type"], "cas");
+        assert_eq!((&cas["from"], &cas["to"]), (&0.into(), &0.into()));
+        assert_eq!(cas["create_if_not_exists"], true);
+        assert_eq!(value.get(), Some(0));
+    }
+
+    #[test]
+    fn picks_the_backend_named_by_the_environment() {
+        assert!(format!("{:?}", from_env()).contains("MaelstromStorage"));
+        std::env::set_var("STORAGE_BACKEND", "memory");
+        assert!(format!("{:?}", from_env()).contains("MemoryStorage"));
+        std::env::remove_var("STORAGE_BACKEND");
+    }
+}
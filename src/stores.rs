@@ -0,0 +1,184 @@
+//! Kafka-style logs, committed offsets and counters, kept in memory.
+
+/// An offset per key.
+pub type Offsets = std::collections::BTreeMap<String, usize>;
+/// The log of each key.
+pub type Logs = std::collections::BTreeMap<String, Log>;
+/// Polled `(offset, message)` pairs per key.
+pub type LogRetrieval = std::collections::BTreeMap<String, Vec<(usize, usize)>>;
+/// The committed offsets of each consumer group.
+pub type OffsetRegistry = std::collections::BTreeMap<String, Offsets>;
+
+/// The most messages a poll returns per key unless it asks for another limit.
+pub const DEFAULT_POLL_LIMIT: usize = 64;
+/// The consumer group of clients that name none, maelstrom clients know
+/// nothing about groups and all share this one.
+pub const DEFAULT_GROUP: &str = "default";
+
+/// Milliseconds since the unix epoch, the unit of log timestamps.
+pub fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("system clock before unix epoch")
+        .as_millis() as u64
+}
+
+/// The messages of one key.
+#[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Log {
+    /// The offset of the first retained message, everything below it was truncated.
+    pub start: usize,
+    /// The retained messages with the millisecond timestamp they were appended at.
+    pub messages: std::collections::VecDeque<(usize, u64)>,
+}
+
+impl Log {
+    /// The offset the next message gets.
+    pub fn end(&self) -> usize {
+        self.start + self.messages.len()
+    }
+}
+
+/// Which messages of a log may be truncated, each limit applies on its own.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct RetentionPolicy {
+    /// Keep at most this many messages.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_messages: Option<usize>,
+    /// Drop messages older than this many milliseconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_age_ms: Option<u64>,
+    /// Drop messages that every consumer group has committed.
+    #[serde(default)]
+    pub below_committed: bool,
+}
+
+/// A poll below the start of a truncated log.
+#[derive(Debug)]
+pub struct OutOfRange {
+    /// The key that was polled.
+    pub key: String,
+    /// The offset that was polled.
+    pub offset: usize,
+    /// The first offset still retained.
+    pub start: usize,
+}
+
+impl std::fmt::Display for OutOfRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "offset {} of key {:?} is below the retained start {}",
+            self.offset, self.key, self.start
+        )
+    }
+}
+
+/// Logs, committed offsets, counters and values, without any locking.
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct Store {
+    /// The kafka logs.
+    pub logs: Logs,
+    /// The committed offsets per consumer group.
+    pub offset_registry: OffsetRegistry,
+    /// The counters.
+    pub counters: std::collections::BTreeMap<String, usize>,
+    /// Retention policies that override the default for their key.
+    #[serde(default)]
+    pub retention: std::collections::BTreeMap<String, RetentionPolicy>,
+    /// The values of the kv operations.
+    #[serde(default)]
+    pub values: std::collections::BTreeMap<String, serde_json::Value>,
+}
+
+impl Store {
+    /// Appends `msg` to the log of `key` and returns its offset.
+    pub fn send(&mut self, key: String, msg: usize, timestamp: u64) -> usize {
+        let log = self.logs.entry(key).or_default();
+        let offset = log.end();
+        log.messages.push_back((msg, timestamp));
+        offset
+    }
+
+    /// Reads at most `limit` messages of each log, starting at its offset.
+    pub fn poll(&self, offsets: Offsets, limit: usize) -> Result<LogRetrieval, OutOfRange> {
+        offsets
+            .into_iter()
+            .map(|(key, offset)| {
+                let Some(log) = self.logs.get(&key) else {
+                    return Ok((key, Vec::new()));
+                };
+                if offset < log.start {
+                    return Err(OutOfRange {
+                        key,
+                        offset,
+                        start: log.start,
+                    });
+                }
+                let messages = log
+                    .messages
+                    .iter()
+                    .skip(offset - log.start)
+                    .take(limit)
+                    .enumerate()
+                    .map(|(index, (message, _))| (offset + index, *message))
+                    .collect();
+                Ok((key, messages))
+            })
+            .collect()
+    }
+
+    /// Commits the offsets of `group`, a committed offset never goes back.
+    pub fn commit_offsets(&mut self, group: String, offsets: Offsets) {
+        let registry = self.offset_registry.entry(group).or_default();
+        offsets.into_iter().for_each(|(key, incoming_offset)| {
+            registry
+                .entry(key)
+                .and_modify(|committed_offset| {
+                    *committed_offset = incoming_offset.max(*committed_offset)
+                })
+                .or_insert(incoming_offset);
+        });
+    }
+
+    /// The offsets `group` committed for `keys`, keys without one are left out.
+    pub fn list_committed_offsets(&self, group: &str, keys: Vec<String>) -> Offsets {
+        let Some(registry) = self.offset_registry.get(group) else {
+            return Offsets::new();
+        };
+        keys.into_iter()
+            .filter(|key| registry.contains_key(key))
+            .map(|key| (key.clone(), *registry.get(&key).unwrap()))
+            .collect()
+    }
+
+    /// Every consumer group that committed an offset.
+    pub fn groups(&self) -> Vec<String> {
+        self.offset_registry.keys().cloned().collect()
+    }
+
+    /// The number of messages past the committed offset of every key `group` committed.
+    pub fn lag(&self, group: &str) -> Offsets {
+        self.offset_registry
+            .get(group)
+            .into_iter()
+            .flatten()
+            .map(|(key, committed_offset)| {
+                let end = self.logs.get(key).map(Log::end).unwrap_or_default();
+                (key.clone(), end.saturating_sub(committed_offset + 1))
+            })
+            .collect()
+    }
+
+    /// The value of the counter `key`, zero if it was never added to.
+    pub fn counter_read(&self, key: &str) -> usize {
+        self.counters.get(key).copied().unwrap_or_default()
+    }
+
+    /// Adds `delta` to the counter `key` and returns its new value.
+    pub fn counter_add(&mut self, key: String, delta: usize) -> usize {
+        let counter = self.counters.entry(key).or_default();
+        *counter += delta;
+        *counter
+    }
+}